@@ -0,0 +1,99 @@
+//! Opening the mapped index against decoding a bincode dump of the same formulae
+
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use camino::Utf8Path;
+use criterion::{criterion_group, criterion_main, Criterion};
+use samogon::index::{build_payload, write_index, Index, IndexHeader};
+use samogon::repo::{Bottle, FormulaStable};
+
+/// About the size of homebrew-core
+const FORMULAE: usize = 7000;
+const PLATFORMS: [&str; 3] = ["arm64_sonoma", "sonoma", "x86_64_linux"];
+const SOURCE_URL: &str = "https://formulae.brew.sh/api/formula.jws.json";
+const LOOKUPS: [&str; 3] = ["formula-0042", "formula-3500", "formula-6999"];
+
+fn formulae() -> Vec<FormulaStable> {
+    (0..FORMULAE)
+        .map(|i| {
+            let name = format!("formula-{:04}", i);
+            let bottles = PLATFORMS
+                .iter()
+                .map(|platform| {
+                    let bottle = Bottle {
+                        cellar: ":any".to_string(),
+                        url: format!(
+                            "https://ghcr.io/v2/homebrew/core/{}/blobs/sha256:{:064x}",
+                            name, i
+                        ),
+                        sha256: format!("{:064x}", i),
+                    };
+                    (platform.to_string(), bottle)
+                })
+                .collect();
+
+            FormulaStable {
+                description: format!("Description of formula number {}", i),
+                version: "1.0.0".to_string(),
+                bottles,
+                deps: (1..4)
+                    .filter_map(|d| i.checked_sub(d * 7))
+                    .map(|dep| format!("formula-{:04}", dep))
+                    .collect(),
+                name,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+fn open_and_look_up(c: &mut Criterion) {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(tmp.path()).unwrap();
+    let formulae = formulae();
+
+    let index_path = dir.join("index.bin");
+    let header = IndexHeader {
+        source_url: SOURCE_URL.to_string(),
+        ..Default::default()
+    };
+    let payload = build_payload(&formulae).unwrap();
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(write_index(&index_path, &header, &payload))
+        .unwrap();
+
+    let bincode_path = dir.join("index.bincode");
+    let by_name: HashMap<_, _> = formulae.into_iter().map(|f| (f.name.clone(), f)).collect();
+    std::fs::write(&bincode_path, bincode::serialize(&by_name).unwrap()).unwrap();
+
+    let mut group = c.benchmark_group("open and look up");
+
+    group.bench_function("mapped index", |b| {
+        b.iter(|| {
+            let index = Index::open(&index_path, SOURCE_URL).unwrap();
+
+            for name in LOOKUPS {
+                let formula = index.get(name).unwrap();
+                black_box(formula.platform_deps("x86_64_linux").count());
+            }
+        })
+    });
+
+    group.bench_function("bincode", |b| {
+        b.iter(|| {
+            let data = std::fs::read(&bincode_path).unwrap();
+            let by_name: HashMap<String, FormulaStable> = bincode::deserialize(&data).unwrap();
+
+            for name in LOOKUPS {
+                black_box(by_name[name].platform_deps("x86_64_linux").count());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, open_and_look_up);
+criterion_main!(benches);
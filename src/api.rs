@@ -0,0 +1,339 @@
+use anyhow::{Context, Result};
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+use crate::config::{HOMEBREW_CELLAR, HOMEBREW_PREFIX};
+use crate::repo::{Bottle, Conflict, Deprecation, FormulaStable, MacosDep};
+
+/// Treats an explicit `null` the same way as a missing field
+fn null_default<'de, D, T>(de: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::deserialize(de)?.unwrap_or_default())
+}
+
+/// A formula as served by formulae.brew.sh
+///
+/// Every field is optional so that a single odd formula does not prevent
+/// the whole index from loading
+#[derive(Deserialize)]
+pub struct ApiFormula {
+    pub name: Option<String>,
+    pub desc: Option<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub versions: ApiVersions,
+    #[serde(default, deserialize_with = "null_default")]
+    pub revision: i64,
+    #[serde(default, deserialize_with = "null_default")]
+    pub dependencies: Vec<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub optional_dependencies: Vec<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub recommended_dependencies: Vec<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub uses_from_macos: Vec<ApiMacosDep>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub uses_from_macos_bounds: Vec<ApiMacosBound>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub bottle: ApiBottleSpec,
+    #[serde(default, deserialize_with = "null_default")]
+    pub aliases: Vec<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub oldnames: Vec<String>,
+    /// Older API versions only carry the most recent old name
+    pub oldname: Option<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub conflicts_with: Vec<String>,
+    /// Parallel to `conflicts_with`
+    #[serde(default, deserialize_with = "null_default")]
+    pub conflicts_with_reasons: Vec<Option<String>>,
+    /// Paths in it are written as `$HOMEBREW_PREFIX` and `$HOMEBREW_CELLAR`
+    pub caveats: Option<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub keg_only: bool,
+    pub keg_only_reason: Option<ApiKegOnlyReason>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub deprecated: bool,
+    pub deprecation_date: Option<String>,
+    pub deprecation_reason: Option<String>,
+    pub deprecation_replacement: Option<String>,
+    pub deprecation_replacement_formula: Option<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub disabled: bool,
+    pub disable_date: Option<String>,
+    pub disable_reason: Option<String>,
+    pub disable_replacement: Option<String>,
+    pub disable_replacement_formula: Option<String>,
+}
+
+/// `reason` is a symbol like `:provided_by_macos` unless `explanation` is given
+#[derive(Deserialize)]
+pub struct ApiKegOnlyReason {
+    pub reason: Option<String>,
+    pub explanation: Option<String>,
+}
+
+impl ApiKegOnlyReason {
+    fn describe(self) -> String {
+        if let Some(explanation) = self.explanation.filter(|e| !e.is_empty()) {
+            return explanation;
+        }
+
+        match self.reason.as_deref().unwrap_or_default() {
+            ":provided_by_macos" => "macOS already provides this software and installing \
+                another version in parallel can cause all kinds of trouble"
+                .to_string(),
+            ":shadowed_by_macos" => "macOS provides similar software and installing this \
+                software in parallel can cause all kinds of trouble"
+                .to_string(),
+            ":versioned_formula" => "this is an alternate version of another formula".to_string(),
+            reason => reason.trim_start_matches(':').replace('_', " "),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct ApiVersions {
+    pub stable: Option<String>,
+}
+
+/// Either a bare name or `{"name": "build"}` / `{"name": ["build", "test"]}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ApiMacosDep {
+    Name(String),
+    Tagged(HashMap<String, ApiDepTags>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ApiDepTags {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ApiDepTags {
+    /// Bottles are prebuilt, so build and test dependencies are irrelevant
+    fn is_build_only(&self) -> bool {
+        let is_build = |t: &String| t == "build" || t == "test";
+
+        match self {
+            ApiDepTags::One(tag) => is_build(tag),
+            ApiDepTags::Many(tags) => !tags.is_empty() && tags.iter().all(is_build),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct ApiMacosBound {
+    pub since: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ApiBottleSpec {
+    pub stable: Option<ApiBottleStable>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiBottleStable {
+    #[serde(default, deserialize_with = "null_default")]
+    pub files: HashMap<String, Bottle>,
+}
+
+/// Just enough of a formula to name it in a warning
+#[derive(Deserialize)]
+struct ApiName {
+    name: Option<String>,
+}
+
+/// Converts the elements of the formula array one by one as they are read,
+/// so that a malformed formula is skipped rather than failing the whole index
+struct FormulaeVisitor;
+
+impl<'de> Visitor<'de> for FormulaeVisitor {
+    type Value = (Vec<FormulaStable>, Vec<String>);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of formulae")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut formulae = vec![];
+        let mut warnings = vec![];
+
+        while let Some(raw) = seq.next_element::<Box<RawValue>>()? {
+            match serde_json::from_str::<ApiFormula>(raw.get()) {
+                Ok(formula) => formulae.extend(formula.into_formula(&mut warnings)),
+                Err(e) => {
+                    let name = serde_json::from_str::<ApiName>(raw.get())
+                        .ok()
+                        .and_then(|n| n.name)
+                        .unwrap_or_else(|| "a formula without a name".to_string());
+
+                    warnings.push(format!("skipped {}: {}", name, e));
+                }
+            }
+        }
+
+        Ok((formulae, warnings))
+    }
+}
+
+/// Parses the formula API array from `reader`, returning the formulae and
+/// warnings about the ones that were skipped
+pub fn parse_formulae(reader: impl Read) -> Result<(Vec<FormulaStable>, Vec<String>)> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+
+    let parsed = de
+        .deserialize_seq(FormulaeVisitor)
+        .context("Failed to parse formulae")?;
+    de.end().context("Failed to parse formulae")?;
+
+    Ok(parsed)
+}
+
+impl ApiFormula {
+    /// Converts the API entry, or explains in `warnings` why it was skipped
+    pub fn into_formula(self, warnings: &mut Vec<String>) -> Option<FormulaStable> {
+        let Some(name) = self.name else {
+            warnings.push("skipped a formula without a name".to_string());
+            return None;
+        };
+
+        let Some(version) = self.versions.stable else {
+            warnings.push(format!("skipped {}: no stable version", name));
+            return None;
+        };
+
+        let bounds = self.uses_from_macos_bounds;
+
+        let uses_from_macos = self
+            .uses_from_macos
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, dep)| {
+                let name = match dep {
+                    ApiMacosDep::Name(name) => name,
+                    ApiMacosDep::Tagged(tagged) => {
+                        let (name, tags) = tagged.into_iter().next()?;
+                        if tags.is_build_only() {
+                            return None;
+                        }
+                        name
+                    }
+                };
+
+                let since = bounds.get(i).and_then(|b| b.since.clone());
+
+                Some(MacosDep { name, since })
+            })
+            .collect();
+
+        let mut reasons = self.conflicts_with_reasons.into_iter();
+        let conflicts = self
+            .conflicts_with
+            .into_iter()
+            .map(|name| Conflict {
+                name,
+                reason: reasons.next().flatten(),
+            })
+            .collect();
+
+        let keg_only = self.keg_only.then(|| {
+            self.keg_only_reason
+                .map(ApiKegOnlyReason::describe)
+                .unwrap_or_default()
+        });
+
+        let caveats = self.caveats.filter(|c| !c.trim().is_empty()).map(|c| {
+            c.replace("$HOMEBREW_CELLAR", HOMEBREW_CELLAR.as_str())
+                .replace("$HOMEBREW_PREFIX", HOMEBREW_PREFIX.as_str())
+        });
+
+        let deprecated = self.deprecated.then(|| Deprecation {
+            date: self.deprecation_date,
+            reason: self.deprecation_reason,
+            replacement: self
+                .deprecation_replacement_formula
+                .or(self.deprecation_replacement),
+        });
+        let disabled = self.disabled.then(|| Deprecation {
+            date: self.disable_date,
+            reason: self.disable_reason,
+            replacement: self
+                .disable_replacement_formula
+                .or(self.disable_replacement),
+        });
+
+        let mut oldnames = self.oldnames;
+        oldnames.extend(self.oldname.filter(|n| !oldnames.contains(n)));
+
+        Some(FormulaStable {
+            name,
+            description: self.desc.unwrap_or_default(),
+            version,
+            revision: self.revision,
+            // Formulae without a stable bottle are kept so that they can still be looked up
+            bottles: self.bottle.stable.map(|b| b.files).unwrap_or_default(),
+            deps: self.dependencies,
+            opt_deps: self.optional_dependencies,
+            rec_deps: self.recommended_dependencies,
+            uses_from_macos,
+            aliases: self.aliases,
+            oldnames,
+            conflicts,
+            keg_only,
+            caveats,
+            deprecated,
+            disabled,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formula(name: &str, extra: &str) -> String {
+        format!(
+            r#"{{"name": "{}", "versions": {{"stable": "1.0"}},
+                "bottle": {{"stable": {{"files": {{"x86_64_linux":
+                    {{"cellar": ":any", "url": "https://example.com/{}", "sha256": "00"}}}}}}}}
+                {}}}"#,
+            name, name, extra
+        )
+    }
+
+    #[test]
+    fn malformed_formulae_are_skipped() {
+        let json = format!(
+            r#"[{}, {}, {}, {}]"#,
+            formula("good", ""),
+            formula("object_dep", r#", "dependencies": [{"foo": "build"}]"#),
+            r#"{"name": "no_cellar", "versions": {"stable": "1.0"},
+                "bottle": {"stable": {"files": {"x86_64_linux": {"url": "u", "sha256": "00"}}}}}"#,
+            formula("no_desc", r#", "desc": null"#),
+        );
+
+        let (formulae, warnings) = parse_formulae(json.as_bytes()).unwrap();
+
+        let names = formulae.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["good", "no_desc"]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("skipped object_dep"));
+        assert!(warnings[1].starts_with("skipped no_cellar"));
+    }
+
+    #[test]
+    fn truncated_array_fails() {
+        let json = format!("[{}", formula("good", ""));
+
+        assert!(parse_formulae(json.as_bytes()).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand};
 
+use crate::config::DEFAULT_HOST_CONNECTIONS;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
@@ -10,5 +12,79 @@ pub struct Args {
 #[derive(Subcommand)]
 pub enum Subcmd {
     // TODO Add subcommands
-    Install { formulae: Vec<String> },
+    /// Install formulae along with their dependencies
+    Install {
+        formulae: Vec<String>,
+        /// Limit the total download rate, in bytes per second, e.g. 512K or 2M
+        #[arg(long, value_parser = parse_bandwidth)]
+        max_bandwidth: Option<u64>,
+        /// Maximum number of concurrent connections to a single host
+        #[arg(long, default_value_t = DEFAULT_HOST_CONNECTIONS)]
+        max_host_connections: usize,
+        /// Install disabled formulae anyway
+        #[arg(long)]
+        force: bool,
+    },
+    /// Search formulae by name or description
+    Search { query: String },
+    /// Show information about a formula
+    Info { formula: String },
+    /// List the dependencies of a formula in install order
+    Deps { formula: String },
+    /// Fetch the newest formula index
+    Update,
+    /// Manage additional formula indexes
+    Tap {
+        #[command(subcommand)]
+        cmd: TapCmd,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TapCmd {
+    /// Add a tap from a URL or a local path to a formula JSON index
+    Add {
+        name: String,
+        url: String,
+        /// Resolve bare formula names to this tap before core
+        #[arg(long)]
+        prefer: bool,
+    },
+    /// Remove a tap
+    Remove { name: String },
+    /// List taps in resolution order
+    List,
+}
+
+/// Parses a byte rate with an optional binary K, M or G suffix
+fn parse_bandwidth(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, mul) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(0) => Err("bandwidth must be positive".to_string()),
+        Ok(n) => n
+            .checked_mul(mul)
+            .ok_or_else(|| format!("{} is too large", s)),
+        Err(_) => Err(format!("{} is not a byte rate like 512K or 2M", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_units() {
+        assert_eq!(parse_bandwidth("512K"), Ok(512 << 10));
+        assert_eq!(parse_bandwidth("2m"), Ok(2 << 20));
+        assert!(parse_bandwidth("0").is_err());
+        assert!(parse_bandwidth("fast").is_err());
+        assert!(parse_bandwidth("18446744073709551615G").is_err());
+    }
 }
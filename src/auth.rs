@@ -0,0 +1,271 @@
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use reqwest::{Client, RequestBuilder, Response, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::config::{ARTIFACT_MIRRORS, BOTTLE_MIRRORS, NETRC, SAMOGON_CONFIG};
+use crate::mirror::DEFAULT_BOTTLE_DOMAIN;
+use crate::registry::registry_get;
+
+/// Credentials for a host serving bottles outside of a container registry
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum Credential {
+    Bearer {
+        token: String,
+    },
+    Basic {
+        login: String,
+        password: Option<String>,
+    },
+}
+
+/// The samogon config file, e.g.
+/// `{"hosts": {"bottles.corp.example": {"login": "ci", "password": "..."}}}`
+#[derive(Deserialize, Default)]
+struct Config {
+    #[serde(default)]
+    hosts: HashMap<String, Credential>,
+}
+
+static CREDENTIALS: OnceLock<HashMap<String, Credential>> = OnceLock::new();
+
+/// Parses `machine`, `login` and `password` entries of a netrc file
+///
+/// The `default` entry is ignored, as it would send the credentials to every
+/// host that an index entry points at
+fn parse_netrc(text: &str) -> HashMap<String, Credential> {
+    let mut creds = HashMap::new();
+    let mut tokens = text.split_whitespace();
+
+    let mut machine = None;
+    let mut login = None;
+    let mut password = None;
+
+    let mut flush = |machine: Option<&str>, login: Option<&str>, password: Option<&str>| {
+        if let (Some(machine), Some(login)) = (machine, login) {
+            creds.insert(
+                machine.to_string(),
+                Credential::Basic {
+                    login: login.to_string(),
+                    password: password.map(str::to_string),
+                },
+            );
+        }
+    };
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                flush(machine, login.take(), password.take());
+                machine = if token == "machine" {
+                    tokens.next()
+                } else {
+                    None
+                };
+            }
+            "login" => login = tokens.next(),
+            "password" => password = tokens.next(),
+            "account" => {
+                tokens.next();
+            }
+            // Macro bodies are free-form, nothing after them can be parsed reliably
+            "macdef" => break,
+            _ => {}
+        }
+    }
+
+    flush(machine, login, password);
+
+    creds
+}
+
+fn load_credentials(
+    netrc: Option<&Utf8Path>,
+    config: &Utf8Path,
+) -> Result<HashMap<String, Credential>> {
+    let mut creds = match netrc {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => parse_netrc(&text),
+            Err(_) => HashMap::new(),
+        },
+        None => HashMap::new(),
+    };
+
+    // The config file takes precedence over netrc
+    if let Ok(data) = std::fs::read(config) {
+        let config: Config =
+            serde_json::from_slice(&data).context(anyhow!("{} is malformed", config))?;
+        creds.extend(config.hosts);
+    }
+
+    Ok(creds)
+}
+
+fn credentials() -> Result<&'static HashMap<String, Credential>> {
+    if let Some(creds) = CREDENTIALS.get() {
+        return Ok(creds);
+    }
+
+    let creds = load_credentials(NETRC.as_deref(), &SAMOGON_CONFIG)?;
+
+    Ok(CREDENTIALS.get_or_init(|| creds))
+}
+
+fn host_of(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+/// Whether `url` is served by GitHub Packages or a registry proxying it, the
+/// only hosts that registry credentials may be sent to
+fn is_registry(url: &str) -> bool {
+    let Some(host) = host_of(url) else {
+        return false;
+    };
+
+    std::iter::once(DEFAULT_BOTTLE_DOMAIN)
+        .chain(BOTTLE_MIRRORS.iter().map(String::as_str))
+        .chain(ARTIFACT_MIRRORS.iter().map(String::as_str))
+        .any(|domain| host_of(domain).as_deref() == Some(host.as_str()))
+}
+
+/// The credential for the host of `url`
+fn credential_for<'a>(creds: &'a HashMap<String, Credential>, url: &Url) -> Option<&'a Credential> {
+    let host = url.host_str()?;
+
+    // A port-qualified entry is more specific
+    url.port()
+        .and_then(|port| creds.get(&format!("{}:{}", host, port)))
+        .or_else(|| creds.get(host))
+}
+
+/// Sends a GET with the credentials that belong to the host of `url`
+///
+/// Registries go through the token exchange, other hosts get credentials
+/// from the config file or netrc, if there are any
+pub async fn authed_get(
+    client: &Client,
+    url: &str,
+    build: impl Fn(RequestBuilder) -> RequestBuilder,
+) -> Result<Response> {
+    if is_registry(url) {
+        return registry_get(client, url, build).await;
+    }
+
+    let cred = credential_for(credentials()?, &Url::parse(url)?);
+
+    let req = build(client.get(url));
+    let req = match cred {
+        Some(Credential::Bearer { token }) => req.bearer_auth(token),
+        Some(Credential::Basic { login, password }) => req.basic_auth(login, password.as_ref()),
+        None => req,
+    };
+
+    Ok(req.send().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{serve, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn login(cred: Option<&Credential>) -> Option<&str> {
+        match cred? {
+            Credential::Basic { login, .. } => Some(login),
+            Credential::Bearer { token } => Some(token),
+        }
+    }
+
+    #[test]
+    fn netrc_default_is_ignored() {
+        let creds = parse_netrc(
+            "machine bottles.example login ci password secret\n\
+             default login anyone password leaked\n",
+        );
+
+        assert_eq!(creds.len(), 1);
+        assert_eq!(login(creds.get("bottles.example")), Some("ci"));
+    }
+
+    #[test]
+    fn port_qualified_entry_wins() {
+        let creds = parse_netrc(
+            "machine bottles.example login bare\n\
+             machine bottles.example:8443 login ported\n",
+        );
+        let url = |url| Url::parse(url).unwrap();
+
+        assert_eq!(
+            login(credential_for(
+                &creds,
+                &url("https://bottles.example:8443/a")
+            )),
+            Some("ported")
+        );
+        assert_eq!(
+            login(credential_for(&creds, &url("https://bottles.example/a"))),
+            Some("bare")
+        );
+        assert_eq!(
+            login(credential_for(&creds, &url("https://other.example:8443/a"))),
+            None
+        );
+    }
+
+    #[test]
+    fn config_overrides_netrc() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+
+        std::fs::write(
+            dir.join("netrc"),
+            "machine bottles.example login netrc\nmachine other.example login other\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{"hosts": {"bottles.example": {"token": "config"}}}"#,
+        )
+        .unwrap();
+
+        let creds = load_credentials(Some(&dir.join("netrc")), &dir.join("config.json")).unwrap();
+
+        assert!(matches!(
+            creds.get("bottles.example"),
+            Some(Credential::Bearer { token }) if token == "config"
+        ));
+        assert_eq!(login(creds.get("other.example")), Some("other"));
+    }
+
+    #[test]
+    fn registry_hosts() {
+        assert!(is_registry(
+            "https://ghcr.io/v2/homebrew/core/wget/blobs/sha256:00"
+        ));
+        assert!(!is_registry("https://bottles.example/wget.tar.gz"));
+        assert!(!is_registry("https://ghcr.io.example/v2/homebrew/core"));
+        assert!(!is_registry("not a url"));
+    }
+
+    /// A 401 from another host is returned as is instead of starting a token exchange
+    #[tokio::test]
+    async fn other_hosts_skip_the_token_exchange() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let url = serve(move |req| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let realm = format!("http://{}/token", req.header("host").unwrap());
+            Response::new(401, "").header("WWW-Authenticate", format!("Bearer realm=\"{}\"", realm))
+        })
+        .await;
+
+        let resp = authed_get(&Client::new(), &url, |req| req).await.unwrap();
+
+        assert_eq!(resp.status(), 401);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
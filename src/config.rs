@@ -1,8 +1,31 @@
 use camino::{Utf8Path, Utf8PathBuf};
 use lazy_static::lazy_static;
+use std::time::Duration;
 
+/// Attempts per bottle URL, transient errors only
 pub const FETCH_RETRIES: u64 = 3;
-pub const MAX_CONCURRENT_FETCHES: usize = 16;
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// A transfer that receives nothing for this long is considered stalled
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longer delays, even when asked for with `Retry-After`, make us give up
+pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Concurrent connections to a single host unless set with `--max-host-connections`
+pub const DEFAULT_HOST_CONNECTIONS: usize = 8;
+/// Bottles fetched, unpacked and poured at once, whatever hosts they come from
+pub const MAX_CONCURRENT_INSTALLS: usize = 16;
+/// Staging directories this old were left behind by an interrupted install
+pub const STALE_STAGING_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Bottles at least this large are fetched in `FETCH_SEGMENTS` parallel ranges
+pub const SEGMENTED_FETCH_MIN_SIZE: u64 = 64 << 20;
+pub const FETCH_SEGMENTS: usize = 8;
+
+/// A whitespace-separated list from the environment
+fn env_list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .map(|x| x.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
 
 lazy_static! {
     pub static ref HOMEBREW_CACHE: Utf8PathBuf = {
@@ -18,5 +41,59 @@ lazy_static! {
             .unwrap_or_else(|_| "/opt/homebrew".to_owned())
             .into()
     };
+    pub static ref HOMEBREW_CELLAR: Utf8PathBuf = {
+        std::env::var("HOMEBREW_CELLAR")
+            .map(|x| x.into())
+            .unwrap_or_else(|_| HOMEBREW_PREFIX.join("Cellar"))
+    };
+    /// How long the cached index is used before checking for a newer one
+    pub static ref INDEX_TTL: Duration = {
+        Duration::from_secs(
+            std::env::var("HOMEBREW_API_AUTO_UPDATE_SECS")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(450),
+        )
+    };
+    /// Extra PEM certificates to trust, e.g. for TLS interception by a corporate proxy
+    pub static ref CA_FILE: Option<Utf8PathBuf> =
+        std::env::var("SAMOGON_CA_FILE").ok().map(Into::into);
+    pub static ref HTTP_PROXY: Option<String> =
+        std::env::var("HTTP_PROXY").or_else(|_| std::env::var("http_proxy")).ok();
+    pub static ref HTTPS_PROXY: Option<String> =
+        std::env::var("HTTPS_PROXY").or_else(|_| std::env::var("https_proxy")).ok();
+    /// Optional limit on the duration of a whole transfer
+    pub static ref FETCH_DEADLINE: Option<Duration> = {
+        std::env::var("SAMOGON_FETCH_TIMEOUT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .map(Duration::from_secs)
+    };
+    /// Mirrors tried before the origin, see `mirror.rs`
+    pub static ref API_MIRRORS: Vec<String> = env_list("HOMEBREW_API_DOMAIN");
+    pub static ref BOTTLE_MIRRORS: Vec<String> = env_list("HOMEBREW_BOTTLE_DOMAIN");
+    pub static ref ARTIFACT_MIRRORS: Vec<String> = env_list("HOMEBREW_ARTIFACT_DOMAIN");
+    /// Credentials for GitHub Packages, exchanged for pull tokens
+    pub static ref GITHUB_PACKAGES_TOKEN: Option<String> =
+        std::env::var("HOMEBREW_GITHUB_PACKAGES_TOKEN").ok();
+    pub static ref GITHUB_PACKAGES_USER: Option<String> =
+        std::env::var("HOMEBREW_GITHUB_PACKAGES_USER").ok();
+    /// Credentials for a private registry proxying GitHub Packages, used as is
+    pub static ref DOCKER_REGISTRY_TOKEN: Option<String> =
+        std::env::var("HOMEBREW_DOCKER_REGISTRY_TOKEN").ok();
+    pub static ref DOCKER_REGISTRY_BASIC_AUTH_TOKEN: Option<String> =
+        std::env::var("HOMEBREW_DOCKER_REGISTRY_BASIC_AUTH_TOKEN").ok();
     pub static ref SAMOGON_DATA_DIR: Utf8PathBuf = { HOMEBREW_PREFIX.join(".samogon") };
+    /// Per-host credentials and other settings, see `auth.rs`
+    pub static ref SAMOGON_CONFIG: Utf8PathBuf = {
+        std::env::var("SAMOGON_CONFIG")
+            .map(|x| x.into())
+            .unwrap_or_else(|_| SAMOGON_DATA_DIR.join("config.json"))
+    };
+    pub static ref NETRC: Option<Utf8PathBuf> = {
+        std::env::var("NETRC")
+            .ok()
+            .or_else(|| std::env::var("HOME").ok().map(|home| format!("{}/.netrc", home)))
+            .map(Into::into)
+    };
 }
@@ -1 +1,30 @@
+use anyhow::{anyhow, Context, Result};
 
+use crate::config::HOMEBREW_CELLAR;
+
+/// Names of formulae with at least one keg in the Cellar
+pub fn installed_kegs() -> Result<Vec<String>> {
+    let dir = match HOMEBREW_CELLAR.read_dir_utf8() {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(anyhow!("while reading {}", *HOMEBREW_CELLAR)),
+    };
+
+    let mut kegs = vec![];
+
+    for entry in dir {
+        let entry = entry?;
+        let has_versions = entry
+            .path()
+            .read_dir_utf8()
+            .is_ok_and(|mut versions| versions.next().is_some());
+
+        if has_versions {
+            kegs.push(entry.file_name().to_string());
+        }
+    }
+
+    kegs.sort();
+
+    Ok(kegs)
+}
@@ -1,44 +1,221 @@
 use anyhow::{anyhow, Context, Result};
 use std::collections::HashSet;
+use std::fmt;
 
-use crate::repo::Repo;
+use crate::index::FormulaRef;
+use crate::repo::{FormulaStable, Repo};
 
-pub fn find_deps(pkgs: &[String], repo: &Repo) -> Result<Vec<String>> {
+/// Returns fully qualified names of `pkgs` and their dependencies, dependencies first
+pub fn find_deps(pkgs: &[String], repo: &Repo, platform: &str) -> Result<Vec<String>> {
     // DFS and topological sort of the dependency DAG
 
     let mut deps = Vec::new();
     let mut visited = HashSet::new();
 
     for p in pkgs {
-        if !visited.contains(p) {
-            find_deps_dfs(p, repo, &mut deps, &mut visited)?;
-        }
+        let formula = repo.resolve(p)?;
+        find_deps_dfs(formula, repo, platform, &mut deps, &mut visited)?;
     }
 
     Ok(deps)
 }
 
 fn find_deps_dfs(
-    package: &str,
+    formula: FormulaRef,
     repo: &Repo,
+    platform: &str,
     deps: &mut Vec<String>,
     visited: &mut HashSet<String>,
 ) -> Result<()> {
-    visited.insert(package.to_string());
+    // The same formula can be reached by its bare and its qualified name
+    let name = formula.full_name();
 
-    let formula = repo.formulae.get(package).context(anyhow!(
-        "Nonexistent package {} listed as a dependency",
-        package
-    ))?;
+    if !visited.insert(name.clone()) {
+        return Ok(());
+    }
 
-    for dep in formula.deps.iter() {
+    for dep in formula.platform_deps(platform) {
         // TODO Handle dependency cycles
-        if !visited.contains(dep) {
-            find_deps_dfs(dep, repo, deps, visited)?;
-        }
+        let dep = repo.get(dep).context(anyhow!(
+            "{} depends on {}, which is not in any formula index; the index may be broken",
+            name,
+            dep
+        ))?;
+        find_deps_dfs(dep, repo, platform, deps, visited)?;
     }
 
-    deps.push(package.to_string());
+    deps.push(name);
 
     Ok(())
 }
+
+/// Two formulae that cannot be installed together
+pub struct Conflict {
+    pub formula: String,
+    pub other: String,
+    pub reason: Option<String>,
+    /// Whether `other` is already installed rather than planned
+    pub installed: bool,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let which = if self.installed {
+            "which is installed"
+        } else {
+            "which is also going to be installed"
+        };
+
+        write!(
+            f,
+            "{} conflicts with {}, {}",
+            self.formula, self.other, which
+        )?;
+
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Kegs in the Cellar are named without the tap
+fn bare_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+/// Both sides usually declare a conflict, so pairs are compared unordered
+fn conflict_key(a: &str, b: &str) -> (String, String) {
+    let (a, b) = (bare_name(a), bare_name(b));
+
+    (a.min(b).to_string(), a.max(b).to_string())
+}
+
+/// Checks `conflicts_with` of the planned formulae and of the installed kegs,
+/// in both directions
+pub fn find_conflicts(
+    planned: &[FormulaStable],
+    installed: &[String],
+    repo: &Repo,
+) -> Result<Vec<Conflict>> {
+    let planned_names = planned
+        .iter()
+        .map(|f| bare_name(&f.name))
+        .collect::<HashSet<_>>();
+
+    let mut conflicts = Vec::<Conflict>::new();
+    let mut seen = HashSet::new();
+
+    for f in planned {
+        for c in &f.conflicts {
+            let other = bare_name(&c.name);
+            let is_planned = planned_names.contains(other);
+
+            if !is_planned && !installed.iter().any(|k| k == other) {
+                continue;
+            }
+
+            if seen.insert(conflict_key(&f.name, other)) {
+                conflicts.push(Conflict {
+                    formula: f.name.clone(),
+                    other: c.name.clone(),
+                    reason: c.reason.clone(),
+                    installed: !is_planned,
+                });
+            }
+        }
+    }
+
+    for keg in installed {
+        if planned_names.contains(keg.as_str()) {
+            continue;
+        }
+
+        let Some(formula) = repo.get(keg) else {
+            continue;
+        };
+
+        for c in formula.load()?.conflicts {
+            let other = bare_name(&c.name);
+
+            if planned_names.contains(other) && seen.insert(conflict_key(other, keg)) {
+                conflicts.push(Conflict {
+                    formula: other.to_string(),
+                    other: keg.clone(),
+                    reason: c.reason,
+                    installed: true,
+                });
+            }
+        }
+    }
+
+    Ok(conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::testing::{formula, repo};
+    use crate::repo::Conflict as Declared;
+
+    fn conflicting(name: &str, others: &[&str]) -> FormulaStable {
+        FormulaStable {
+            conflicts: others
+                .iter()
+                .map(|other| Declared {
+                    name: other.to_string(),
+                    reason: Some(format!("both install {}", name)),
+                })
+                .collect(),
+            ..formula(name)
+        }
+    }
+
+    fn pairs(conflicts: &[Conflict]) -> Vec<(&str, &str, bool)> {
+        conflicts
+            .iter()
+            .map(|c| (c.formula.as_str(), c.other.as_str(), c.installed))
+            .collect()
+    }
+
+    #[test]
+    fn planned_against_planned() {
+        let planned = [conflicting("tool", &["tool-ng"]), formula("tool-ng")];
+        let repo = repo(&planned, vec![]);
+
+        let conflicts = find_conflicts(&planned, &[], &repo).unwrap();
+
+        assert_eq!(pairs(&conflicts), [("tool", "tool-ng", false)]);
+    }
+
+    #[test]
+    fn planned_against_installed() {
+        let planned = [formula("tool")];
+        let repo = repo(
+            &[formula("tool"), conflicting("tool-ng", &["tool"])],
+            vec![],
+        );
+
+        let conflicts = find_conflicts(&planned, &["tool-ng".to_string()], &repo).unwrap();
+
+        assert_eq!(pairs(&conflicts), [("tool", "tool-ng", true)]);
+        assert_eq!(conflicts[0].reason.as_deref(), Some("both install tool-ng"));
+    }
+
+    #[test]
+    fn declared_on_both_sides_is_reported_once() {
+        let planned = [
+            conflicting("tool", &["tool-ng"]),
+            conflicting("tool-ng", &["tool"]),
+        ];
+        let repo = repo(&planned, vec![]);
+
+        let conflicts = find_conflicts(&planned, &[], &repo).unwrap();
+        assert_eq!(pairs(&conflicts), [("tool", "tool-ng", false)]);
+
+        let installed = ["tool-ng".to_string()];
+        let conflicts = find_conflicts(&planned[..1], &installed, &repo).unwrap();
+        assert_eq!(pairs(&conflicts), [("tool", "tool-ng", true)]);
+    }
+}
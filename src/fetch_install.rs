@@ -3,31 +3,74 @@ use async_compression::tokio::bufread::GzipDecoder;
 use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
 use indicatif::{MultiProgress, MultiProgressAlignment, ProgressBar, ProgressDrawTarget};
-use reqwest::Client;
+use reqwest::header::{
+    HeaderMap, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER,
+};
+use reqwest::{Response, StatusCode};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::os::unix::prelude::MetadataExt;
+use std::sync::Arc;
 use std::time::{self, Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, SeekFrom};
-use tokio::task::JoinSet;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, DuplexStream, SeekFrom,
+};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::{spawn_blocking, JoinHandle, JoinSet};
 use tokio_stream::StreamExt;
 use tokio_tar::Archive;
 
-use crate::config::{FETCH_RETRIES, HOMEBREW_CACHE, MAX_CONCURRENT_FETCHES};
+use crate::auth::authed_get;
+use crate::config::{
+    FETCH_RETRIES, HOMEBREW_CACHE, MAX_CONCURRENT_INSTALLS, SAMOGON_DATA_DIR,
+    SEGMENTED_FETCH_MIN_SIZE, STALE_STAGING_AGE,
+};
+use crate::http::{client, idle_timeout};
+use crate::keg;
+use crate::mirror::bottle_urls;
 use crate::platform::get_current_platform;
-use crate::repo::FormulaStable;
-use crate::ui::{common_bar_prefix, fetch_bar_style, total_bar_style};
+use crate::repo::{FormulaStable, Repo};
+use crate::retry::{backoff, classify, parse_retry_after, ErrorClass};
+use crate::segmented::{
+    read_state as read_segment_state, remove_state as remove_segment_state, segmented_get,
+    SegmentState,
+};
+use crate::throttle;
+use crate::ui::{caveats, common_bar_prefix, fetch_bar_style, total_bar_style};
 use crate::util::{file_digest, fmt_digest, normalize_path};
 
-const GITHUB_AUTH_TOKEN: &str = "QQ==";
-
 // TODO change all string paths to PathBufs
 
 #[derive(Error, Debug)]
-enum BottleFetchErr {
+pub enum BottleFetchErr {
     #[error("bottle download is corrupted")]
     DownloadCorrupted,
+    #[error("resumed bottle download is corrupted")]
+    ResumedDownloadCorrupted,
+    #[error("server resumed at byte {0} instead of {1}")]
+    BadContentRange(u64, u64),
+    #[error("file has changed on the server since the download started")]
+    ChangedOnServer,
+    #[error("server responded with {0}")]
+    BadStatus(StatusCode),
+    #[error("server responded with {0}, retry after {1:?}")]
+    RetryAfter(StatusCode, Duration),
+}
+
+/// The error for an unexpected response, keeping the delay the server asked for
+pub fn status_error(resp: &Response) -> BottleFetchErr {
+    let retry_after = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+
+    match retry_after {
+        Some(delay) => BottleFetchErr::RetryAfter(resp.status(), delay),
+        None => BottleFetchErr::BadStatus(resp.status()),
+    }
 }
 
 async fn check_cached(path: &Utf8Path, checksum: &str, progress: &mut ProgressBar) -> Result<bool> {
@@ -47,102 +90,367 @@ async fn check_cached(path: &Utf8Path, checksum: &str, progress: &mut ProgressBa
     Ok(fmt_digest(file_digest(&mut file, progress).await?) == checksum)
 }
 
-/// Fetches the file and returns its checksum
-async fn github_get(
+/// Sidecar of a partial download holding the `If-Range` validator it was fetched with
+fn validator_path(path: &Utf8Path) -> Utf8PathBuf {
+    path.with_file_name(format!("{}.validator", path.file_name().unwrap()))
+}
+
+/// A strong `ETag` or `Last-Modified`, whichever the server sent
+fn response_validator(headers: &HeaderMap) -> Option<String> {
+    let etag = headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"));
+
+    etag.or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok()))
+        .map(str::to_string)
+}
+
+/// Parses `Content-Range: bytes <start>-<end>/<total>` into (start, total)
+pub fn parse_content_range(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (span, total) = range.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = span.split_once('-')?;
+
+    Some((start.parse().ok()?, total.parse().ok()))
+}
+
+/// A bottle in the download cache
+struct FetchedBottle {
+    path: Utf8PathBuf,
+    /// Set when the bottle was unpacked while it was being downloaded
+    unpacked: Option<Utf8PathBuf>,
+}
+
+/// Unpacks a bottle into a staging directory as it is being downloaded
+///
+/// Dropped without `finish`, e.g. when the download is cancelled, it stops the
+/// extractor and removes the staging directory
+struct StreamUnpack {
+    tx: Option<DuplexStream>,
+    /// Empty once handed out by `finish` or removed
+    dir: Utf8PathBuf,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl StreamUnpack {
+    fn start(path: &Utf8Path, staging: &Utf8Path) -> StreamUnpack {
+        let (tx, rx) = tokio::io::duplex(1 << 18);
+        let dir = staging_dir(staging, path);
+
+        StreamUnpack {
+            tx: Some(tx),
+            dir: dir.clone(),
+            task: Some(tokio::spawn(async move {
+                tokio::fs::create_dir_all(&dir).await?;
+                unpack_stream(BufReader::new(rx), &dir).await
+            })),
+        }
+    }
+
+    async fn feed(&mut self, chunk: &[u8]) {
+        // The extractor has stopped reading, the bottle will be unpacked from the cache
+        if let Some(tx) = &mut self.tx {
+            if tx.write_all(chunk).await.is_err() {
+                self.tx = None;
+            }
+        }
+    }
+
+    /// Waits for the extractor and returns the staging directory if it unpacked everything
+    async fn finish(mut self) -> Option<Utf8PathBuf> {
+        drop(self.tx.take());
+
+        match self.task.take()?.await {
+            Ok(Ok(())) => Some(std::mem::take(&mut self.dir)),
+            _ => {
+                self.discard().await;
+                None
+            }
+        }
+    }
+
+    async fn discard(mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+
+        let _ = tokio::fs::remove_dir_all(std::mem::take(&mut self.dir)).await;
+    }
+}
+
+impl Drop for StreamUnpack {
+    fn drop(&mut self) {
+        if self.dir.as_str().is_empty() {
+            return;
+        }
+
+        let task = self.task.take();
+        let dir = std::mem::take(&mut self.dir);
+
+        // The extractor has to stop before its directory can be removed for good
+        let cleanup = async move {
+            if let Some(task) = task {
+                task.abort();
+                let _ = task.await;
+            }
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+        };
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(cleanup);
+        }
+    }
+}
+
+/// Fetches the file and verifies its checksum
+///
+/// A resumed download continues a partial file only if the server confirms,
+/// through `If-Range`, that it still serves the same content, and starts over
+/// if the server cannot satisfy the range.
+/// A download from scratch is unpacked on the fly, the staging directory is
+/// returned once the checksum matches. Resumed downloads and segmented ones,
+/// of bottles of at least `SEGMENTED_FETCH_MIN_SIZE`, are not, `None` is
+/// returned for them and the bottle is unpacked from the cache afterwards
+///
+/// Without `segmented` even large files are fetched with a single connection
+async fn http_get(
     url: &str,
     path: &Utf8Path,
+    staging: &Utf8Path,
     resume: bool,
+    segmented: bool,
     checksum: &str,
     progress: &mut ProgressBar,
-) -> Result<()> {
+) -> Result<Option<Utf8PathBuf>> {
     let mut hash_ctx = Sha256::new();
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(path)
         .await?;
 
-    if !resume {
-        file.set_len(0).await?;
+    let validator_path = validator_path(path);
+
+    if resume {
+        if let Some(state) = read_segment_state(path).await.filter(|s| s.is_for(url)) {
+            progress.println(format!("resuming {}", url));
+            return segmented_or_fresh(url, path, staging, state, checksum, progress).await;
+        }
     }
+    remove_segment_state(path).await;
 
-    let offset = file.metadata().await?.size();
+    let validator = if resume {
+        tokio::fs::read_to_string(&validator_path).await.ok()
+    } else {
+        None
+    };
 
-    file.seek(SeekFrom::Start(0)).await?;
+    let mut offset = match validator {
+        Some(_) => file.metadata().await?.size(),
+        None => 0,
+    };
 
-    if offset > 0 && resume {
+    if offset > 0 {
         progress.println(format!("resuming {}", url));
-        progress.set_message("resuming download...");
+        progress.set_message("rehashing partial download...");
+        progress.set_length(offset);
+        progress.set_position(0);
+
+        file.seek(SeekFrom::Start(0)).await?;
+        hash_ctx = file_digest(&mut file, progress).await?;
     } else {
         progress.set_position(0);
-        progress.set_message("opening connection...");
     }
 
-    let mut resp = Client::new()
-        .get(url)
-        .bearer_auth(GITHUB_AUTH_TOKEN)
-        .header("Range", format!("bytes: {}-", offset))
-        .send()
-        .await?;
+    progress.set_message("waiting for a connection...");
+    let slot = throttle::host_slot(url).await;
+    progress.set_message("opening connection...");
+
+    let mut resp = idle_timeout(authed_get(client()?, url, |req| match &validator {
+        Some(validator) if offset > 0 => req
+            .header(RANGE, format!("bytes={}-", offset))
+            .header(IF_RANGE, validator),
+        _ => req,
+    }))
+    .await?;
+
+    let size = match resp.status() {
+        StatusCode::PARTIAL_CONTENT if offset > 0 => {
+            let (start, total) = parse_content_range(resp.headers())
+                .context("malformed Content-Range in a partial response")?;
+
+            if start != offset {
+                let _ = tokio::fs::remove_file(&validator_path).await;
+                return Err(BottleFetchErr::BadContentRange(start, offset).into());
+            }
 
-    let size = resp
-        .content_length()
-        .context("No Content-Length received")?;
+            total.or(resp.content_length().map(|len| offset + len))
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+            // The partial file is longer than what the server has now
+            drop(resp);
+            drop(slot);
+            drop(file);
+            let _ = tokio::fs::remove_file(&validator_path).await;
+            tokio::fs::remove_file(path).await?;
+
+            return Box::pin(http_get(
+                url, path, staging, false, segmented, checksum, progress,
+            ))
+            .await;
+        }
+        StatusCode::OK => {
+            // Either a fresh download, or the partial file is stale
+            offset = 0;
+            hash_ctx = Sha256::new();
+            file.set_len(0).await?;
+
+            let validator = response_validator(resp.headers());
+            let accepts_ranges = resp
+                .headers()
+                .get(ACCEPT_RANGES)
+                .is_some_and(|v| v.as_bytes() == b"bytes");
+
+            // Segments can only be fetched safely when guarded with a validator
+            if let (Some(size), Some(validator), true) =
+                (resp.content_length(), &validator, accepts_ranges)
+            {
+                if segmented && size >= SEGMENTED_FETCH_MIN_SIZE {
+                    // Segments take connections of their own
+                    drop(resp);
+                    drop(slot);
+                    drop(file);
+                    let _ = tokio::fs::remove_file(&validator_path).await;
+                    let state = SegmentState::new(url, size, validator.clone());
+                    return segmented_or_fresh(url, path, staging, state, checksum, progress).await;
+                }
+            }
+
+            match validator {
+                Some(validator) => tokio::fs::write(&validator_path, validator).await?,
+                None => {
+                    let _ = tokio::fs::remove_file(&validator_path).await;
+                }
+            }
+
+            resp.content_length()
+        }
+        _ => return Err(status_error(&resp).into()),
+    };
 
     progress.set_message("");
-    progress.set_length(size);
+    progress.set_length(size.unwrap_or(0));
     progress.set_position(offset);
 
-    file.seek(SeekFrom::End(0)).await?;
-    file.set_len(size).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let resumed = offset > 0;
+    let mut unpack = (!resumed).then(|| StreamUnpack::start(path, staging));
+
+    let streamed: Result<()> = async {
+        while let Some(chunk) = idle_timeout(resp.chunk()).await? {
+            throttle::consume(chunk.len()).await;
+
+            progress.inc(chunk.len() as _);
+            hash_ctx.update(&chunk);
+            file.write_all(&chunk).await?;
+
+            if let Some(unpack) = &mut unpack {
+                unpack.feed(&chunk).await;
+            }
+        }
+
+        Ok(file.flush().await?)
+    }
+    .await;
 
-    while let Some(chunk) = resp.chunk().await? {
-        progress.inc(chunk.len() as _);
-        hash_ctx.update(&chunk);
-        file.write_all(&chunk).await?;
+    if let Err(e) = streamed {
+        if let Some(unpack) = unpack {
+            unpack.discard().await;
+        }
+        return Err(e);
     }
 
-    file.seek(SeekFrom::Start(0)).await?;
+    // Whatever was downloaded is useless for resuming either way
+    let _ = tokio::fs::remove_file(&validator_path).await;
 
     if fmt_digest(hash_ctx) == checksum {
-        Ok(())
+        Ok(match unpack {
+            Some(unpack) => unpack.finish().await,
+            None => None,
+        })
     } else {
-        Err(BottleFetchErr::DownloadCorrupted.into())
+        if let Some(unpack) = unpack {
+            unpack.discard().await;
+        }
+
+        if resumed {
+            Err(BottleFetchErr::ResumedDownloadCorrupted.into())
+        } else {
+            Err(BottleFetchErr::DownloadCorrupted.into())
+        }
+    }
+}
+
+/// Fetches the file in segments, starting over with a single connection if the
+/// file has changed on the server since the segments were started
+async fn segmented_or_fresh(
+    url: &str,
+    path: &Utf8Path,
+    staging: &Utf8Path,
+    state: SegmentState,
+    checksum: &str,
+    progress: &mut ProgressBar,
+) -> Result<Option<Utf8PathBuf>> {
+    match segmented_get(url, path, state, checksum, progress).await {
+        Err(e) if matches!(e.downcast_ref(), Some(BottleFetchErr::ChangedOnServer)) => {
+            progress.println(format!("{} {} has changed, starting over", "!".bold(), url));
+            remove_segment_state(path).await;
+            tokio::fs::remove_file(path).await?;
+
+            Box::pin(http_get(
+                url, path, staging, false, false, checksum, progress,
+            ))
+            .await
+        }
+        res => res.map(|()| None),
     }
 }
 
 /// Fetches a bottle or gets it from cache and returns its local path
-///
-/// TODO multithreaded fetching of the same big bottle
 async fn fetch_bottle(
     platform: &str,
     formula: &FormulaStable,
+    downloads: &Utf8Path,
+    staging: &Utf8Path,
     progress: &mut ProgressBar,
-) -> Result<Utf8PathBuf> {
+) -> Result<FetchedBottle> {
     progress.set_message("searching cache...");
 
-    let bottle_entry = (&formula.bottles).get(platform).context(anyhow!(
-        "Package {} is unavailable for {}",
-        formula.name,
+    let bottle_entry = formula.bottle_for(platform).context(anyhow!(
+        "no bottle available for {}; building from source is not supported",
         platform
     ))?;
 
+    keg::check_cellar(&bottle_entry.cellar)?;
+
     let url = bottle_entry.url.as_str();
 
+    // Tap formulae are named `<tap>/<formula>`
     let basename = format!(
         "{}--{}.{}.bottle.tar.gz",
-        &formula.name,
+        formula.keg_name(),
         formula.version_fmt(),
         platform
     );
 
     let url_digest = sha256::digest(url);
 
-    // We can assume that paths are strings since only macOS is supported
-    let downloads_cache = HOMEBREW_CACHE.join("downloads");
-
-    let cache_path = downloads_cache.join(format!("{}--{}", url_digest, basename));
+    let cache_path = downloads.join(format!("{}--{}", url_digest, basename));
 
     let incomplete_path =
         cache_path.with_file_name(format!("{}.incomplete", cache_path.file_name().unwrap()));
@@ -150,44 +458,164 @@ async fn fetch_bottle(
     let checksum = &bottle_entry.sha256;
 
     match check_cached(&cache_path, checksum, progress).await {
-        Ok(true) => return Ok(cache_path),
+        Ok(true) => {
+            return Ok(FetchedBottle {
+                path: cache_path,
+                unpacked: None,
+            })
+        }
         Ok(false) => tokio::fs::remove_file(&cache_path)
             .await
             .context("while removing corrupted cache entry")?,
         _ => {} // Err(e) => progress.println(format!(" !! cache check failed due to {:?}", e)),
     }
 
-    if let Ok(_) = github_get(&url, &incomplete_path, true, checksum, progress).await {
-        tokio::fs::rename(&incomplete_path, &cache_path)
-            .await
-            .context("while moving incomplete -> cache")?;
-        return Ok(cache_path);
-    }
-    // else warn(resuming failed)
+    tokio::fs::create_dir_all(downloads)
+        .await
+        .context(anyhow!("while creating {}", downloads))?;
 
-    for _ in 1..FETCH_RETRIES {
-        if let Ok(_) = github_get(&url, &incomplete_path, false, checksum, progress).await {
-            tokio::fs::rename(&incomplete_path, &cache_path)
-                .await
-                .context("while moving incomplete -> cache")?;
-            return Ok(cache_path);
+    let urls = bottle_urls(url);
+    let mut last_err = None;
+
+    for (i, url) in urls.iter().enumerate() {
+        match fetch_with_retries(url, &incomplete_path, staging, checksum, progress).await {
+            Ok(unpacked) => {
+                tokio::fs::rename(&incomplete_path, &cache_path)
+                    .await
+                    .context("while moving incomplete -> cache")?;
+                return Ok(FetchedBottle {
+                    path: cache_path,
+                    unpacked,
+                });
+            }
+            Err(e) => {
+                if i + 1 < urls.len() {
+                    progress.println(format!(
+                        "{} {} failed, trying the next mirror: {}",
+                        "!".bold(),
+                        url,
+                        e
+                    ));
+                }
+                last_err = Some(e);
+            }
         }
-        // else warn(retry {i})
     }
 
-    // return the error on the last try
+    Err(last_err
+        .unwrap()
+        .context(anyhow!("while downloading {}", formula.name)))
+}
 
-    github_get(&url, &incomplete_path, false, checksum, progress)
-        .await
-        .context(anyhow!("while getting {} from github", formula.name))?;
+/// Retries transient failures with a backoff, every attempt resumes what the
+/// previous ones have left
+async fn fetch_with_retries(
+    url: &str,
+    incomplete_path: &Utf8Path,
+    staging: &Utf8Path,
+    checksum: &str,
+    progress: &mut ProgressBar,
+) -> Result<Option<Utf8PathBuf>> {
+    let mut attempt = 1;
 
-    tokio::fs::rename(&incomplete_path, &cache_path)
+    loop {
+        let e = match http_get(
+            url,
+            incomplete_path,
+            staging,
+            true,
+            true,
+            checksum,
+            progress,
+        )
         .await
-        .context("while moving incomplete -> cache")?;
-    Ok(cache_path)
+        {
+            Ok(unpacked) => return Ok(unpacked),
+            Err(e) => e,
+        };
+
+        let delay = match classify(&e) {
+            ErrorClass::Transient(retry_after) if attempt < FETCH_RETRIES => {
+                backoff(attempt, retry_after)
+            }
+            _ => None,
+        };
+
+        let Some(delay) = delay else {
+            return Err(e);
+        };
+
+        progress.println(format!(
+            "{} {} attempt {}/{} failed: {:#}, retrying in {:.1}s",
+            "!".bold(),
+            url,
+            attempt,
+            FETCH_RETRIES,
+            e,
+            delay.as_secs_f32()
+        ));
+        progress.set_message("waiting to retry...");
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Removes what interrupted installs have left in `staging`, recent directories
+/// may belong to an install that is still running
+async fn remove_stale_staging(staging: &Utf8Path) {
+    let Ok(mut entries) = tokio::fs::read_dir(staging).await else {
+        return;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let stale = entry
+            .metadata()
+            .await
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > STALE_STAGING_AGE);
+
+        if stale {
+            let _ = tokio::fs::remove_dir_all(entry.path()).await;
+        }
+    }
+}
+
+/// A fresh directory in `staging` to unpack a bottle into
+fn staging_dir(staging: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    staging.join(sha256::digest(format!(
+        "{}{}",
+        path.to_string(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .to_string()
+    )))
+}
+
+async fn unpack_stream(reader: impl AsyncRead + Unpin, unpack_dir: &Utf8Path) -> Result<()> {
+    // TODO check if the file is really gzipped and maybe support other compression algos
+    let gz_reader = GzipDecoder::new(BufReader::with_capacity(1 << 18, reader));
+    let mut archive = Archive::new(gz_reader);
+    let mut ents = archive.entries().context("while unpacking archive")?;
+
+    while let Some(ent) = ents.next().await {
+        ent.context("while uncompressing archive item")?
+            .unpack_in(unpack_dir)
+            .await?;
+    }
+
+    Ok(())
 }
 
-async fn unpack_archive(path: &Utf8Path, progress: &mut ProgressBar) -> Result<Utf8PathBuf> {
+async fn unpack_archive(
+    path: &Utf8Path,
+    staging: &Utf8Path,
+    progress: &mut ProgressBar,
+) -> Result<Utf8PathBuf> {
     progress.set_position(0);
     progress.set_length(0);
     progress.set_message("unpacking bottle archive");
@@ -195,49 +623,93 @@ async fn unpack_archive(path: &Utf8Path, progress: &mut ProgressBar) -> Result<U
     let file = File::open(path).await.context("while opening archive")?;
     progress.set_length(file.metadata().await?.len() as _);
     let progress_read = progress.wrap_async_read(file);
-    let reader = BufReader::with_capacity(1 << 18, progress_read);
-    // TODO check if the file is really gzipped and maybe support other compression algos
-    let gz_reader = GzipDecoder::new(reader);
-    let mut archive = Archive::new(gz_reader);
-    let mut ents = archive.entries().context("while unpacking archive")?;
 
-    let unpack_dir: Utf8PathBuf = Utf8PathBuf::from_path_buf(std::env::temp_dir())
-        .unwrap()
-        .join(sha256::digest(format!(
-            "{}{}",
-            path.to_string(),
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string()
-        )));
+    let unpack_dir = staging_dir(staging, path);
 
     tokio::fs::create_dir_all(&unpack_dir)
         .await
         .context("while creating cache directory")?;
 
-    while let Some(ent) = ents.next().await {
-        ent.context("while uncompressing archive item")?
-            .unpack_in(&unpack_dir)
-            .await?;
-    }
+    unpack_stream(progress_read, &unpack_dir).await?;
 
     Ok(unpack_dir)
 }
 
 async fn pour_bottle(
     formula: &FormulaStable,
-    path: &Utf8Path,
+    bottle: FetchedBottle,
+    staging: &Utf8Path,
     progress: &mut ProgressBar,
 ) -> Result<()> {
-    let tmp = unpack_archive(path, progress).await?;
+    let staging = match bottle.unpacked {
+        Some(dir) => dir,
+        None => unpack_archive(&bottle.path, staging, progress).await?,
+    };
 
-    Ok(())
+    progress.set_message("pouring");
+
+    let name = formula.keg_name().to_string();
+    let keg_only = formula.keg_only.is_some();
+    let dir = staging.clone();
+
+    let poured = spawn_blocking(move || {
+        let keg = keg::pour(&dir, &name)?;
+        keg::relocate(&keg)?;
+        keg::link_opt(&keg, &name)?;
+
+        if !keg_only {
+            keg::link_prefix(&keg, &name)?;
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+
+    poured
 }
 
-async fn stream_one(formula: FormulaStable, screen: MultiProgress) -> Result<()> {
+/// Why a keg was not poured, when the reason is elsewhere
+#[derive(Error, Debug)]
+#[error("dependency {0} was not installed")]
+struct DependencyFailed(String);
+
+/// Resolves with the name of the first dependency that fails, never if none does
+async fn failed_dependency(deps: &[(String, watch::Receiver<bool>)]) -> String {
+    let mut js = JoinSet::new();
+
+    for (name, dep) in deps {
+        let (name, mut dep) = (name.clone(), dep.clone());
+        // The sender is dropped without sending if the dependency has failed
+        js.spawn(async move {
+            dep.wait_for(|&poured| poured)
+                .await
+                .is_err()
+                .then_some(name)
+        });
+    }
+
+    while let Some(res) = js.join_next().await {
+        if let Ok(Some(name)) = res {
+            return name;
+        }
+    }
+
+    std::future::pending().await
+}
+
+/// Fetches a bottle right away, but pours it only after all of its
+/// dependencies have been poured
+async fn stream_one(
+    formula: FormulaStable,
+    deps: Vec<(String, watch::Receiver<bool>)>,
+    poured: watch::Sender<bool>,
+    staging: Utf8PathBuf,
+    screen: MultiProgress,
+) -> Result<()> {
     let platform = get_current_platform();
+    let downloads = HOMEBREW_CACHE.join("downloads");
 
     let mut progress = screen.insert_from_back(
         1,
@@ -249,19 +721,42 @@ async fn stream_one(formula: FormulaStable, screen: MultiProgress) -> Result<()>
                 formula.name,
                 formula.version_fmt()
             ))
-            .with_position(0)
+            .with_position(0),
     );
 
     progress.tick();
 
-    let path = fetch_bottle(platform, &formula, &mut progress)
-        .await
-        .context(anyhow!("while fetching {}", formula.name))?;
+    // Do not bother fetching what could not be poured anyway
+    let fetched = tokio::select! {
+        res = fetch_bottle(platform, &formula, &downloads, &staging, &mut progress) => {
+            res.context(anyhow!("while fetching {}", formula.name))
+        }
+        name = failed_dependency(&deps) => Err(DependencyFailed(name).into()),
+    };
+
+    let bottle = match fetched {
+        Ok(bottle) => bottle,
+        Err(e) => {
+            screen.remove(&progress);
+            return Err(e);
+        }
+    };
+
+    progress.set_message("waiting for dependencies...");
 
-    pour_bottle(&formula, &path, &mut progress)
+    for (name, mut dep) in deps {
+        if dep.wait_for(|&poured| poured).await.is_err() {
+            screen.remove(&progress);
+            return Err(DependencyFailed(name).into());
+        }
+    }
+
+    pour_bottle(&formula, bottle, &staging, &mut progress)
         .await
         .context(anyhow!("while pouring {}", formula.name))?;
 
+    poured.send_replace(true);
+
     screen.remove(&progress);
     let progress = screen.insert(0, progress);
 
@@ -270,7 +765,14 @@ async fn stream_one(formula: FormulaStable, screen: MultiProgress) -> Result<()>
     Ok(())
 }
 
-pub async fn stream_all(formulae: Vec<FormulaStable>) -> Result<()> {
+/// Installs formulae given in topological order, as returned by `deps::find_deps`
+pub async fn stream_all(formulae: Vec<FormulaStable>, repo: &Repo) -> Result<()> {
+    let platform = get_current_platform();
+    // Inside the prefix, so that kegs can be moved into the Cellar without copying
+    let staging = SAMOGON_DATA_DIR.join("staging");
+
+    remove_stale_staging(&staging).await;
+
     let progress = MultiProgress::new();
     progress.set_alignment(MultiProgressAlignment::Top);
 
@@ -281,38 +783,545 @@ pub async fn stream_all(formulae: Vec<FormulaStable>) -> Result<()> {
 
     total_bar.enable_steady_tick(Duration::from_millis(500));
 
+    let mut poured: HashMap<String, watch::Receiver<bool>> = HashMap::new();
     let mut js = JoinSet::new();
+    let slots = Arc::new(Semaphore::new(MAX_CONCURRENT_INSTALLS));
+    let mut notes = HashMap::new();
+    let mut planned = vec![];
 
-    let mut iter = formulae.into_iter();
-
-    for _ in 0..MAX_CONCURRENT_FETCHES {
-        if let Some(i) = iter.next() {
-            js.spawn(stream_one(i, progress.clone()));
-        } else {
-            break;
+    for formula in formulae {
+        if let Some(caveats) = caveats(&formula) {
+            notes.insert(formula.name.clone(), caveats);
         }
+
+        // Dependencies outside of the plan are not ours to wait for, the rest
+        // may be named by an alias or an old name, as `find_deps` resolves them
+        let deps = formula
+            .platform_deps(platform)
+            .filter_map(|d| repo.get(d).map(|dep| dep.full_name()))
+            .filter_map(|d| poured.get(&d).map(|rx| (d.clone(), rx.clone())))
+            .collect();
+
+        let (tx, rx) = watch::channel(false);
+        poured.insert(formula.name.clone(), rx);
+
+        // Taken in install order, so that a task only ever waits for
+        // dependencies which already hold a slot or are done
+        let slot = slots.clone().acquire_owned().await?;
+
+        let name = formula.name.clone();
+        planned.push(name.clone());
+        let task = stream_one(formula, deps, tx, staging.clone(), progress.clone());
+        js.spawn(async move {
+            let res = task.await;
+            drop(slot);
+            (name, res)
+        });
     }
 
+    let mut failed = vec![];
+    let mut installed = vec![];
+
     while let Some(res) = js.join_next().await {
-        match res? {
-            Ok(_) => match iter.next() {
-                Some(f) => {
-                    total_bar.inc(1);
-                    js.spawn(stream_one(f, progress.clone()));
-                }
-                None => {}
-            },
+        let (name, res) = res?;
+
+        match res {
+            Ok(()) => {
+                total_bar.inc(1);
+                installed.push(name);
+            }
             Err(e) => {
-                progress.println(format!(
-                    "{} {} due to the following error:\n{:?}",
-                    "!".bold(),
-                    "aborting".bold().red(),
-                    e
-                ))?;
-                js.abort_all();
+                if let Some(dep) = e.downcast_ref::<DependencyFailed>() {
+                    progress.println(format!("{} skipping {}: {}", "!".bold(), name, dep))?;
+                } else {
+                    progress.println(format!(
+                        "{} {} {} due to the following error:\n{:?}",
+                        "!".bold(),
+                        "failed".bold().red(),
+                        name,
+                        e
+                    ))?;
+                }
+                failed.push(name);
             }
         }
     }
 
+    // Caveats of the formulae that made it, in install order
+    let summary = planned
+        .iter()
+        .filter(|name| installed.contains(name))
+        .filter_map(|name| {
+            Some(format!(
+                "{} {}\n{}",
+                "==>".bold(),
+                name.bold(),
+                notes.get(name)?
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    if !summary.is_empty() {
+        progress.println(format!("\n -> caveats:\n\n{}\n", summary.join("\n\n")))?;
+    }
+
+    if !failed.is_empty() {
+        bail!("could not install {}", failed.join(", "));
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Bottle;
+    use crate::test_server::{ranged, serve, Request, Response};
+    use async_compression::tokio::write::GzipEncoder;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    const VALIDATOR: &str = "\"v1\"";
+
+    fn bottle() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A server for `ranged` that records the requests it gets
+    async fn serve_bottle(
+        body: Vec<u8>,
+        validator: &'static str,
+    ) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+
+        let url = serve(move |req| {
+            let resp = ranged(&req, &body, validator);
+            log.lock().unwrap().push(req);
+            resp
+        })
+        .await;
+
+        (format!("{}/bottle.tar.gz", url), requests)
+    }
+
+    /// Streamed unpacks go next to the download
+    fn staging(path: &Utf8Path) -> Utf8PathBuf {
+        path.with_file_name("staging")
+    }
+
+    /// A partial download of `prefix` fetched with `validator`
+    fn partial(prefix: &[u8], validator: &str) -> (tempfile::TempDir, Utf8PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("bottle.incomplete");
+
+        std::fs::write(&path, prefix).unwrap();
+        std::fs::write(validator_path(&path), validator).unwrap();
+
+        (tmp, path)
+    }
+
+    #[tokio::test]
+    async fn resume_requests_the_rest() {
+        let body = bottle();
+        let (url, requests) = serve_bottle(body.clone(), VALIDATOR).await;
+        let (_tmp, path) = partial(&body[..30_000], VALIDATOR);
+
+        let checksum = sha256::digest(&body[..]);
+        http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(!validator_path(&path).exists());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("range"), Some("bytes=30000-"));
+        assert_eq!(requests[0].header("if-range"), Some(VALIDATOR));
+    }
+
+    #[tokio::test]
+    async fn resumed_prefix_is_hashed() {
+        let body = bottle();
+        let (url, _) = serve_bottle(body.clone(), VALIDATOR).await;
+
+        let mut prefix = body[..30_000].to_vec();
+        prefix[10] ^= 0xff;
+        let (_tmp, path) = partial(&prefix, VALIDATOR);
+
+        let checksum = sha256::digest(&body[..]);
+        let err = http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BottleFetchErr::ResumedDownloadCorrupted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn mismatched_content_range_is_rejected() {
+        let body = bottle();
+        let served = body.clone();
+        let url = serve(move |_| {
+            Response::new(206, &served[20_000..]).header(
+                "Content-Range",
+                format!("bytes 20000-{}/{}", served.len() - 1, served.len()),
+            )
+        })
+        .await;
+        let (_tmp, path) = partial(&body[..30_000], VALIDATOR);
+
+        let checksum = sha256::digest(&body[..]);
+        let err = http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BottleFetchErr::BadContentRange(20_000, 30_000))
+        ));
+        // The next attempt starts over
+        assert!(!validator_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn stale_partial_download_is_replaced() {
+        let body = bottle();
+        let (url, requests) = serve_bottle(body.clone(), "\"v2\"").await;
+        let (_tmp, path) = partial(&[0xff; 30_000], VALIDATOR);
+
+        let checksum = sha256::digest(&body[..]);
+        http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+
+        // The server got the old validator and ignored the range
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("if-range"), Some(VALIDATOR));
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_range_starts_over() {
+        let body = bottle();
+        let (url, requests) = serve_bottle(body.clone(), VALIDATOR).await;
+        let (_tmp, path) = partial(&[0; 150_000], VALIDATOR);
+
+        let checksum = sha256::digest(&body[..]);
+        http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].header("range"), Some("bytes=150000-"));
+        assert_eq!(requests[1].header("range"), None);
+    }
+
+    #[tokio::test]
+    async fn no_range_without_a_validator() {
+        let body = bottle();
+        let (url, requests) = serve_bottle(body.clone(), VALIDATOR).await;
+        let (_tmp, path) = partial(&body[..30_000], VALIDATOR);
+        std::fs::remove_file(validator_path(&path)).unwrap();
+
+        let checksum = sha256::digest(&body[..]);
+        http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(requests.lock().unwrap()[0].header("range"), None);
+    }
+
+    #[tokio::test]
+    async fn changed_file_starts_over() {
+        let body = bottle();
+        let (url, requests) = serve_bottle(body.clone(), "\"v2\"").await;
+        let (_tmp, path) = partial(&[], VALIDATOR);
+
+        let state = SegmentState::new(&url, body.len() as u64, VALIDATOR.to_string());
+        let checksum = sha256::digest(&body[..]);
+        segmented_or_fresh(
+            &url,
+            &path,
+            &staging(&path),
+            state,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(read_segment_state(&path).await.is_none());
+
+        // Segments got the whole file, then a single connection got it again
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .rev()
+            .skip(1)
+            .all(|r| r.header("range").is_some()));
+        assert_eq!(requests.last().unwrap().header("range"), None);
+    }
+
+    #[tokio::test]
+    async fn segments_of_another_mirror_are_ignored() {
+        let body = bottle();
+        let checksum = sha256::digest(&body[..]);
+        let (_tmp, path) = partial(&[], VALIDATOR);
+        std::fs::remove_file(validator_path(&path)).unwrap();
+
+        let mirror = serve(|_| Response::new(503, "")).await;
+        let state = SegmentState::new(&mirror, body.len() as u64, VALIDATOR.to_string());
+        segmented_get(&mirror, &path, state, &checksum, &mut ProgressBar::hidden())
+            .await
+            .unwrap_err();
+        assert!(read_segment_state(&path).await.is_some());
+
+        let (url, requests) = serve_bottle(body.clone(), VALIDATOR).await;
+        http_get(
+            &url,
+            &path,
+            &staging(&path),
+            true,
+            true,
+            &checksum,
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(read_segment_state(&path).await.is_none());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("range"), None);
+    }
+
+    /// A gzipped tar of `files` files of 64 KiB that compress about as well as binaries
+    async fn bottle_archive(files: usize) -> Vec<u8> {
+        let mut tar = tokio_tar::Builder::new(GzipEncoder::new(vec![]));
+        let mut seed = 1u32;
+
+        for i in 0..files {
+            let data = (0..1 << 16)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (seed >> 16) as u8 & 0x3f
+                })
+                .collect::<Vec<_>>();
+
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+
+            let name = format!("tool/1.0/share/tool/{}.dat", i);
+            tar.append_data(&mut header, name, &data[..]).await.unwrap();
+        }
+
+        let mut gz = tar.into_inner().await.unwrap();
+        gz.shutdown().await.unwrap();
+        gz.into_inner()
+    }
+
+    /// Over a slow link, unpacking while downloading finishes before
+    /// downloading and then unpacking the cached bottle
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_unpack_overlaps_the_download() {
+        let body = bottle_archive(200).await;
+        let checksum = sha256::digest(&body[..]);
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let mut progress = ProgressBar::hidden();
+
+        // About a second per download
+        let rate = body.len();
+        let served = body.clone();
+        let url = serve(move |req| ranged(&req, &served, VALIDATOR).rate(rate)).await;
+
+        let streamed = dir.join("streamed.incomplete");
+        let start = Instant::now();
+        let staging = http_get(
+            &url,
+            &streamed,
+            &dir.join("staging"),
+            false,
+            true,
+            &checksum,
+            &mut progress,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let streamed_time = start.elapsed();
+        tokio::fs::remove_dir_all(staging).await.unwrap();
+
+        let cached = dir.join("cached.incomplete");
+        let start = Instant::now();
+        let mut resp = client().unwrap().get(&url).send().await.unwrap();
+        let mut file = File::create(&cached).await.unwrap();
+        let mut hash_ctx = Sha256::new();
+        while let Some(chunk) = resp.chunk().await.unwrap() {
+            hash_ctx.update(&chunk);
+            file.write_all(&chunk).await.unwrap();
+        }
+        file.flush().await.unwrap();
+        assert_eq!(fmt_digest(hash_ctx), checksum);
+        let staging = unpack_archive(&cached, &dir.join("staging"), &mut progress)
+            .await
+            .unwrap();
+        let cached_time = start.elapsed();
+        tokio::fs::remove_dir_all(staging).await.unwrap();
+
+        assert!(
+            streamed_time < cached_time,
+            "streamed in {:?}, downloaded and unpacked in {:?}",
+            streamed_time,
+            cached_time
+        );
+    }
+
+    #[tokio::test]
+    async fn tap_bottle_is_cached_under_its_keg_name() {
+        let body = bottle();
+        let (url, _) = serve_bottle(body.clone(), VALIDATOR).await;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+
+        let formula = FormulaStable {
+            name: "mycorp/tool".to_string(),
+            version: "1.0".to_string(),
+            bottles: HashMap::from([(
+                "x86_64_linux".to_string(),
+                Bottle {
+                    cellar: ":any".to_string(),
+                    url: url.clone(),
+                    sha256: sha256::digest(&body[..]),
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let downloads = dir.join("downloads");
+        let fetched = fetch_bottle(
+            "x86_64_linux",
+            &formula,
+            &downloads,
+            &dir.join("staging"),
+            &mut ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        let expected = format!(
+            "{}--tool--1.0_0.x86_64_linux.bottle.tar.gz",
+            sha256::digest(url.as_str())
+        );
+        assert_eq!(fetched.path, downloads.join(expected));
+        assert_eq!(std::fs::read(&fetched.path).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn dropped_unpack_removes_its_staging_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+
+        let mut unpack = StreamUnpack::start(&dir.join("bottle.incomplete"), dir);
+        let archive = bottle_archive(4).await;
+        unpack.feed(&archive[..archive.len() / 2]).await;
+        let staging = unpack.dir.clone();
+
+        while !staging.join("tool").exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(unpack);
+
+        for _ in 0..100 {
+            if !staging.exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} was left behind", staging);
+    }
+
+    #[tokio::test]
+    async fn stale_staging_dirs_are_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let staging = Utf8Path::from_path(tmp.path()).unwrap();
+
+        for name in ["stale", "recent"] {
+            std::fs::create_dir_all(staging.join(name).join("liba")).unwrap();
+        }
+        std::fs::File::open(staging.join("stale"))
+            .unwrap()
+            .set_modified(SystemTime::now() - STALE_STAGING_AGE * 2)
+            .unwrap();
+
+        remove_stale_staging(staging).await;
+
+        assert!(!staging.join("stale").exists());
+        assert!(staging.join("recent").exists());
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::config::{
+    CA_FILE, CONNECT_TIMEOUT, FETCH_DEADLINE, HTTPS_PROXY, HTTP_PROXY, IDLE_TIMEOUT,
+};
+use crate::platform::get_current_platform;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+#[derive(Error, Debug)]
+#[error("nothing was received for {0:?}, the transfer has stalled")]
+pub struct Stalled(pub Duration);
+
+fn build_client() -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(format!(
+            "samogon/{} ({})",
+            env!("CARGO_PKG_VERSION"),
+            get_current_platform()
+        ))
+        .connect_timeout(CONNECT_TIMEOUT)
+        // GHCR speaks HTTP/2, so bottles share one connection when ALPN allows
+        .http2_adaptive_window(true);
+
+    if let Some(deadline) = *FETCH_DEADLINE {
+        builder = builder.timeout(deadline);
+    }
+
+    // Set explicitly so that NO_PROXY applies to both
+    if let Some(proxy) = &*HTTP_PROXY {
+        builder = builder.proxy(
+            Proxy::http(proxy)
+                .context(anyhow!("HTTP_PROXY {} is invalid", proxy))?
+                .no_proxy(NoProxy::from_env()),
+        );
+    }
+    if let Some(proxy) = &*HTTPS_PROXY {
+        builder = builder.proxy(
+            Proxy::https(proxy)
+                .context(anyhow!("HTTPS_PROXY {} is invalid", proxy))?
+                .no_proxy(NoProxy::from_env()),
+        );
+    }
+
+    if let Some(path) = &*CA_FILE {
+        let pem = std::fs::read(path).context(anyhow!("while reading {}", path))?;
+        let certs = Certificate::from_pem_bundle(&pem)
+            .context(anyhow!("{} is not a PEM certificate bundle", path))?;
+
+        if certs.is_empty() {
+            bail!("{} contains no certificates", path);
+        }
+
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// The client shared by all requests of this run, so that connections are reused
+pub fn client() -> Result<&'static Client> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+
+    let client = build_client()?;
+
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// Fails with `Stalled` if `fut` does not complete within `IDLE_TIMEOUT`,
+/// meant for waiting on response headers and on every chunk of the body
+pub async fn idle_timeout<T, E: Into<anyhow::Error>>(
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T> {
+    match tokio::time::timeout(IDLE_TIMEOUT, fut).await {
+        Ok(res) => res.map_err(Into::into),
+        Err(_) => Err(Stalled(IDLE_TIMEOUT).into()),
+    }
+}
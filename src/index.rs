@@ -0,0 +1,655 @@
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ops::Deref;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::platform::macos_dep_applies;
+use crate::repo::FormulaStable;
+
+const MAGIC: &[u8; 8] = b"SMGNIDX\0";
+
+/// Bump whenever `FormulaStable` or the payload layout changes
+const SCHEMA_VERSION: u32 = 7;
+
+// File layout: magic, schema version (u32 LE), header length (u32 LE), header, payload
+//
+// Payload layout, all integers are u32 LE:
+//   count, refs base, macos deps base, aliases base, alias count, strings base
+//   `count` entries sorted by name, see `Entry`
+//   refs area: (offset, length) pairs of dependency names
+//   macos deps area: (name offset, name length, since offset, since length)
+//   aliases area: (name offset, name length, entry number) sorted by name,
+//     for both aliases and old names
+//   strings area: names, descriptions and bincode-encoded `FormulaStable`s
+//
+// Offsets inside an area are relative to its base
+
+const PAYLOAD_HEADER_LEN: usize = 24;
+const ENTRY_LEN: usize = 40;
+const REF_LEN: usize = 8;
+const MACOS_DEP_LEN: usize = 16;
+const ALIAS_LEN: usize = 12;
+
+/// Fields of a fixed-size entry, in u32 units
+enum Entry {
+    NameOff,
+    NameLen,
+    DescOff,
+    DescLen,
+    BlobOff,
+    BlobLen,
+    DepsOff,
+    DepsCount,
+    MacosOff,
+    MacosCount,
+}
+
+#[derive(Error, Debug)]
+pub enum IndexErr {
+    #[error("not a samogon index")]
+    BadMagic,
+    #[error("index is truncated")]
+    Truncated,
+    #[error("index has schema version {0}, expected {SCHEMA_VERSION}")]
+    SchemaMismatch(u32),
+    #[error("index was fetched from {0}, expected {1}")]
+    SourceMismatch(String, String),
+    #[error("index checksum mismatch")]
+    ChecksumMismatch,
+}
+
+/// Describes where and when the cached index was fetched
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct IndexHeader {
+    pub source_url: String,
+    pub fetched_at: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredHeader {
+    header: IndexHeader,
+    checksum: [u8; 32],
+}
+
+enum Backing {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Backing::Mapped(map) => map,
+            Backing::Owned(data) => data,
+        }
+    }
+}
+
+/// A formula index that is queried in place, without deserializing it as a whole
+pub struct Index {
+    pub header: IndexHeader,
+    /// The tap the formulae come from, `None` for core; not stored in the cache
+    pub namespace: Option<String>,
+    data: Backing,
+    payload_start: usize,
+    /// The cache the index was mapped from, `None` if it was never on disk
+    path: Option<Utf8PathBuf>,
+    /// Of the payload as written, `None` if it was never on disk
+    checksum: Option<[u8; 32]>,
+    count: usize,
+    refs_base: usize,
+    macos_base: usize,
+    aliases_base: usize,
+    alias_count: usize,
+    strings_base: usize,
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, IndexErr> {
+    let bytes = data.get(at..at + 4).ok_or(IndexErr::Truncated)?;
+
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl Index {
+    /// Maps the cache and validates its header
+    ///
+    /// Hashing the payload would touch every page of the map, so that is left
+    /// to `verify_checksum` before the cache is refreshed, and to `load` when a
+    /// formula cannot be read
+    pub fn open(path: &Utf8Path, source_url: &str) -> Result<Index> {
+        let file = std::fs::File::open(path)?;
+        // The cache is only ever replaced by a rename, never modified in place
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.get(..MAGIC.len()).ok_or(IndexErr::Truncated)? != MAGIC {
+            return Err(IndexErr::BadMagic.into());
+        }
+
+        let version = read_u32(&map, MAGIC.len())?;
+        if version != SCHEMA_VERSION {
+            return Err(IndexErr::SchemaMismatch(version).into());
+        }
+
+        let header_len = read_u32(&map, MAGIC.len() + 4)? as usize;
+        let header_end = MAGIC.len() + 8 + header_len;
+
+        let stored: StoredHeader = bincode::deserialize(
+            map.get(MAGIC.len() + 8..header_end)
+                .ok_or(IndexErr::Truncated)?,
+        )
+        .map_err(|_| IndexErr::Truncated)?;
+
+        if stored.header.source_url != source_url {
+            return Err(
+                IndexErr::SourceMismatch(stored.header.source_url, source_url.to_string()).into(),
+            );
+        }
+
+        Index::new(
+            stored.header,
+            Backing::Mapped(map),
+            header_end,
+            Some((path.to_owned(), stored.checksum)),
+        )
+    }
+
+    /// Wraps a freshly built payload which could not be mapped from disk
+    pub fn from_payload(header: IndexHeader, payload: Vec<u8>) -> Result<Index> {
+        Index::new(header, Backing::Owned(payload), 0, None)
+    }
+
+    fn new(
+        header: IndexHeader,
+        data: Backing,
+        payload_start: usize,
+        stored: Option<(Utf8PathBuf, [u8; 32])>,
+    ) -> Result<Index> {
+        let payload = &data[payload_start..];
+
+        let count = read_u32(payload, 0)? as usize;
+        let refs_base = read_u32(payload, 4)? as usize;
+        let macos_base = read_u32(payload, 8)? as usize;
+        let aliases_base = read_u32(payload, 12)? as usize;
+        let alias_count = read_u32(payload, 16)? as usize;
+        let strings_base = read_u32(payload, 20)? as usize;
+
+        if PAYLOAD_HEADER_LEN + count * ENTRY_LEN > refs_base
+            || refs_base > macos_base
+            || macos_base > aliases_base
+            || aliases_base + alias_count * ALIAS_LEN > strings_base
+            || strings_base > payload.len()
+        {
+            return Err(IndexErr::Truncated.into());
+        }
+
+        Ok(Index {
+            header,
+            namespace: None,
+            data,
+            payload_start,
+            path: stored.as_ref().map(|(path, _)| path.clone()),
+            checksum: stored.map(|(_, checksum)| checksum),
+            count,
+            refs_base,
+            macos_base,
+            aliases_base,
+            alias_count,
+            strings_base,
+        })
+    }
+
+    /// Checks the payload against the checksum it was written with
+    pub fn verify_checksum(&self) -> Result<(), IndexErr> {
+        match self.checksum {
+            Some(checksum) if Sha256::digest(self.payload())[..] != checksum => {
+                Err(IndexErr::ChecksumMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes a cache that does not match its checksum, so that it is rebuilt
+    /// on the next run, and returns its path
+    fn remove_if_corrupted(&self) -> Option<&Utf8Path> {
+        let path = self.path.as_deref()?;
+        self.verify_checksum().is_err().then(|| {
+            let _ = std::fs::remove_file(path);
+            path
+        })
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.payload_start..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn u32_at(&self, at: usize) -> usize {
+        read_u32(self.payload(), at).unwrap_or(0) as usize
+    }
+
+    fn bytes_at(&self, off: usize, len: usize) -> &[u8] {
+        let start = self.strings_base + off;
+
+        self.payload().get(start..start + len).unwrap_or_default()
+    }
+
+    fn str_at(&self, off: usize, len: usize) -> &str {
+        std::str::from_utf8(self.bytes_at(off, len)).unwrap_or_default()
+    }
+
+    fn entry_field(&self, i: usize, field: Entry) -> usize {
+        self.u32_at(PAYLOAD_HEADER_LEN + i * ENTRY_LEN + field as usize * 4)
+    }
+
+    fn name_of(&self, i: usize) -> &str {
+        self.str_at(
+            self.entry_field(i, Entry::NameOff),
+            self.entry_field(i, Entry::NameLen),
+        )
+    }
+
+    /// Index of the first entry whose name is not less than `name`
+    fn lower_bound(&self, name: &str) -> usize {
+        let (mut lo, mut hi) = (0, self.count);
+
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.name_of(mid) < name {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo
+    }
+
+    pub fn get(&self, name: &str) -> Option<FormulaRef<'_>> {
+        let i = self.lower_bound(name);
+
+        (i < self.count && self.name_of(i) == name).then_some(FormulaRef { index: self, i })
+    }
+
+    fn alias_field(&self, i: usize, field: usize) -> usize {
+        self.u32_at(self.aliases_base + i * ALIAS_LEN + field * 4)
+    }
+
+    /// Looks a formula up by one of its aliases or old names
+    pub fn get_alias(&self, name: &str) -> Option<FormulaRef<'_>> {
+        let (mut lo, mut hi) = (0, self.alias_count);
+
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.alias_name(mid) < name {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        (lo < self.alias_count && self.alias_name(lo) == name)
+            .then(|| self.alias_target(lo))
+            .flatten()
+    }
+
+    fn alias_name(&self, i: usize) -> &str {
+        self.str_at(self.alias_field(i, 0), self.alias_field(i, 1))
+    }
+
+    fn alias_target(&self, i: usize) -> Option<FormulaRef<'_>> {
+        let i = self.alias_field(i, 2);
+
+        (i < self.count).then_some(FormulaRef { index: self, i })
+    }
+
+    /// All aliases and old names with the formulae they point to
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, FormulaRef<'_>)> {
+        (0..self.alias_count).filter_map(move |i| Some((self.alias_name(i), self.alias_target(i)?)))
+    }
+
+    /// All formulae whose names start with `prefix`, in name order
+    pub fn with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = FormulaRef<'a>> {
+        (self.lower_bound(prefix)..self.count)
+            .map(move |i| FormulaRef { index: self, i })
+            .take_while(move |f| f.name().starts_with(prefix))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = FormulaRef<'_>> {
+        (0..self.count).map(move |i| FormulaRef { index: self, i })
+    }
+}
+
+/// A formula inside an `Index`; everything except `load` is zero-copy
+#[derive(Clone, Copy)]
+pub struct FormulaRef<'a> {
+    index: &'a Index,
+    i: usize,
+}
+
+impl<'a> FormulaRef<'a> {
+    fn field(&self, field: Entry) -> usize {
+        self.index.entry_field(self.i, field)
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.index.name_of(self.i)
+    }
+
+    /// The name qualified with the tap, e.g. `mycorp/tool`
+    pub fn full_name(&self) -> String {
+        match &self.index.namespace {
+            Some(tap) => format!("{}/{}", tap, self.name()),
+            None => self.name().to_string(),
+        }
+    }
+
+    pub fn description(&self) -> &'a str {
+        self.index
+            .str_at(self.field(Entry::DescOff), self.field(Entry::DescLen))
+    }
+
+    pub fn deps(&self) -> impl Iterator<Item = &'a str> + 'a {
+        let index = self.index;
+        let base = index.refs_base + self.field(Entry::DepsOff);
+
+        (0..self.field(Entry::DepsCount)).map(move |j| {
+            let at = base + j * REF_LEN;
+            index.str_at(index.u32_at(at), index.u32_at(at + 4))
+        })
+    }
+
+    /// `uses_from_macos` entries as (name, since) pairs
+    pub fn uses_from_macos(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> + 'a {
+        let index = self.index;
+        let base = index.macos_base + self.field(Entry::MacosOff);
+
+        (0..self.field(Entry::MacosCount)).map(move |j| {
+            let at = base + j * MACOS_DEP_LEN;
+            let since = index.str_at(index.u32_at(at + 8), index.u32_at(at + 12));
+
+            (
+                index.str_at(index.u32_at(at), index.u32_at(at + 4)),
+                (!since.is_empty()).then_some(since),
+            )
+        })
+    }
+
+    /// Same as `FormulaStable::platform_deps`
+    pub fn platform_deps(&self, platform: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.deps().chain(
+            self.uses_from_macos()
+                .filter(move |(_, since)| macos_dep_applies(*since, platform))
+                .map(|(name, _)| name),
+        )
+    }
+
+    /// Deserializes the full formula
+    pub fn load(&self) -> Result<FormulaStable> {
+        let mut formula: FormulaStable = bincode::deserialize(
+            self.index
+                .bytes_at(self.field(Entry::BlobOff), self.field(Entry::BlobLen)),
+        )
+        .map_err(|e| match self.index.remove_if_corrupted() {
+            Some(path) => anyhow!(
+                "the cached index {} is corrupted, it has been removed and is rebuilt on the next run",
+                path
+            ),
+            None => anyhow!("deserialization error in {}: {}", self.name(), e),
+        })?;
+
+        formula.name = self.full_name();
+
+        Ok(formula)
+    }
+}
+
+#[derive(Default)]
+struct PayloadBuilder {
+    entries: Vec<u8>,
+    refs: Vec<u8>,
+    macos: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+fn push_u32(buf: &mut Vec<u8>, val: usize) {
+    buf.extend((val as u32).to_le_bytes());
+}
+
+impl PayloadBuilder {
+    /// Appends to the strings area, returning (offset, length)
+    fn push_bytes(&mut self, bytes: &[u8]) -> (usize, usize) {
+        let off = self.strings.len();
+        self.strings.extend(bytes);
+        (off, bytes.len())
+    }
+
+    fn push_formula(&mut self, formula: &FormulaStable) -> Result<()> {
+        let name = self.push_bytes(formula.name.as_bytes());
+        let desc = self.push_bytes(formula.description.as_bytes());
+        let blob = self.push_bytes(&bincode::serialize(formula)?);
+
+        let deps_off = self.refs.len();
+        for dep in &formula.deps {
+            let (off, len) = self.push_bytes(dep.as_bytes());
+            push_u32(&mut self.refs, off);
+            push_u32(&mut self.refs, len);
+        }
+
+        let macos_off = self.macos.len();
+        for dep in &formula.uses_from_macos {
+            let (off, len) = self.push_bytes(dep.name.as_bytes());
+            let (since_off, since_len) =
+                self.push_bytes(dep.since.as_deref().unwrap_or_default().as_bytes());
+            for val in [off, len, since_off, since_len] {
+                push_u32(&mut self.macos, val);
+            }
+        }
+
+        for val in [
+            name.0,
+            name.1,
+            desc.0,
+            desc.1,
+            blob.0,
+            blob.1,
+            deps_off,
+            formula.deps.len(),
+            macos_off,
+            formula.uses_from_macos.len(),
+        ] {
+            push_u32(&mut self.entries, val);
+        }
+
+        Ok(())
+    }
+}
+
+/// Lays out the formulae for `Index`
+pub fn build_payload<'a>(formulae: impl IntoIterator<Item = &'a FormulaStable>) -> Result<Vec<u8>> {
+    let mut sorted = formulae.into_iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+    sorted.dedup_by(|a, b| a.name == b.name);
+
+    let mut builder = PayloadBuilder::default();
+
+    for formula in &sorted {
+        builder.push_formula(formula)?;
+    }
+
+    // Real names always win over aliases, and the first formula claiming an alias wins
+    let mut aliases = sorted
+        .iter()
+        .enumerate()
+        .flat_map(|(i, f)| f.aliases.iter().chain(&f.oldnames).map(move |a| (a, i)))
+        .filter(|(alias, _)| {
+            sorted
+                .binary_search_by(|f| f.name.as_str().cmp(alias))
+                .is_err()
+        })
+        .collect::<Vec<_>>();
+    aliases.sort();
+    aliases.dedup_by(|a, b| a.0 == b.0);
+
+    let mut alias_area = vec![];
+    for (alias, i) in &aliases {
+        let (off, len) = builder.push_bytes(alias.as_bytes());
+        for val in [off, len, *i] {
+            push_u32(&mut alias_area, val);
+        }
+    }
+
+    let refs_base = PAYLOAD_HEADER_LEN + builder.entries.len();
+    let macos_base = refs_base + builder.refs.len();
+    let aliases_base = macos_base + builder.macos.len();
+    let strings_base = aliases_base + alias_area.len();
+
+    if strings_base + builder.strings.len() > u32::MAX as usize {
+        return Err(anyhow!("index is too large"));
+    }
+
+    let mut payload = Vec::with_capacity(strings_base + builder.strings.len());
+
+    for val in [
+        sorted.len(),
+        refs_base,
+        macos_base,
+        aliases_base,
+        aliases.len(),
+        strings_base,
+    ] {
+        push_u32(&mut payload, val);
+    }
+
+    payload.extend(builder.entries);
+    payload.extend(builder.refs);
+    payload.extend(builder.macos);
+    payload.extend(alias_area);
+    payload.extend(builder.strings);
+
+    Ok(payload)
+}
+
+/// Writes the cache through a temporary file, so that it is never left half-written
+pub async fn write_index(path: &Utf8Path, header: &IndexHeader, payload: &[u8]) -> Result<()> {
+    let stored = bincode::serialize(&StoredHeader {
+        header: header.clone(),
+        checksum: Sha256::digest(payload).into(),
+    })?;
+
+    let tmp_path = path.with_file_name(format!(
+        "{}.{}.tmp",
+        path.file_name().unwrap(),
+        std::process::id()
+    ));
+
+    let mut file = tokio::fs::File::create(&tmp_path)
+        .await
+        .context("while creating a temporary index file")?;
+
+    file.write_all(MAGIC).await?;
+    file.write_all(&SCHEMA_VERSION.to_le_bytes()).await?;
+    file.write_all(&(stored.len() as u32).to_le_bytes()).await?;
+    file.write_all(&stored).await?;
+    file.write_all(payload).await?;
+    file.sync_all().await?;
+
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .context("while moving the temporary index file into place")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::FormulaStable;
+
+    fn formula(name: &str, deps: &[&str]) -> FormulaStable {
+        FormulaStable {
+            name: name.to_string(),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            aliases: vec![format!("{}-alias", name)],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn checksum_is_checked_lazily() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path()).unwrap().join("index.bin");
+        let header = IndexHeader {
+            source_url: "file:///formulae".to_string(),
+            ..Default::default()
+        };
+        let formulae = [formula("liba", &[]), formula("tool", &["liba"])];
+        write_index(&path, &header, &build_payload(&formulae).unwrap())
+            .await
+            .unwrap();
+
+        let index = Index::open(&path, "file:///formulae").unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index.get("tool").unwrap().deps().collect::<Vec<_>>(),
+            ["liba"]
+        );
+        assert_eq!(index.get_alias("liba-alias").unwrap().name(), "liba");
+        index.verify_checksum().unwrap();
+
+        // Corrupt the last byte of the payload, which is a string
+        let mut data = std::fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        let index = Index::open(&path, "file:///formulae").unwrap();
+        assert!(matches!(
+            index.verify_checksum(),
+            Err(IndexErr::ChecksumMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn corrupted_formula_removes_the_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path()).unwrap().join("index.bin");
+        let header = IndexHeader {
+            source_url: "file:///formulae".to_string(),
+            ..Default::default()
+        };
+        let formulae = [formula("liba", &[]), formula("tool", &["liba"])];
+        write_index(&path, &header, &build_payload(&formulae).unwrap())
+            .await
+            .unwrap();
+
+        let index = Index::open(&path, "file:///formulae").unwrap();
+        let liba = index.get("liba").unwrap();
+        let blob = index.payload_start + index.strings_base + liba.field(Entry::BlobOff);
+        let blob = blob..blob + liba.field(Entry::BlobLen);
+        drop(index);
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[blob].fill(0xff);
+        std::fs::write(&path, data).unwrap();
+
+        let index = Index::open(&path, "file:///formulae").unwrap();
+        let err = index.get("liba").unwrap().load().unwrap_err();
+
+        assert!(err.to_string().contains("is corrupted"));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use camino::Utf8Path;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::pss::{Signature, VerifyingKey};
+use rsa::signature::Verifier;
+use rsa::RsaPublicKey;
+use serde::Deserialize;
+use sha2::Sha512;
+use std::collections::HashMap;
+
+use crate::config::SAMOGON_DATA_DIR;
+
+/// Keys shipped with samogon, as (key id, PEM); `<data dir>/keys` is only
+/// meant for keys rotated in after a release
+///
+/// TODO vendor homebrew-1.pem from Homebrew/brew (Library/Homebrew/api) as
+/// `("homebrew-1", include_str!("../keys/homebrew-1.pem"))`
+const EMBEDDED_KEYS: &[(&str, &str)] = &[];
+
+/// Public keys by key id
+pub type TrustedKeys = HashMap<String, RsaPublicKey>;
+
+/// A JWS in the general JSON serialization, as served by formulae.brew.sh
+#[derive(Deserialize)]
+struct Jws {
+    payload: String,
+    signatures: Vec<JwsSignature>,
+}
+
+#[derive(Deserialize)]
+struct JwsSignature {
+    protected: String,
+    header: Option<JwsHeader>,
+    signature: String,
+}
+
+#[derive(Deserialize, Default)]
+struct JwsHeader {
+    kid: Option<String>,
+    alg: Option<String>,
+    b64: Option<bool>,
+}
+
+/// Public keys to verify the index with, by key id
+///
+/// Rotated keys are picked up from `<data dir>/keys/<key id>.pem`
+pub fn trusted_keys() -> Result<TrustedKeys> {
+    load_keys(&SAMOGON_DATA_DIR.join("keys"))
+}
+
+/// The embedded keys along with `<key id>.pem` files from `keys_dir`
+pub fn load_keys(keys_dir: &Utf8Path) -> Result<TrustedKeys> {
+    let mut keys = HashMap::new();
+
+    for (kid, pem) in EMBEDDED_KEYS {
+        let key = RsaPublicKey::from_public_key_pem(pem)
+            .context(anyhow!("embedded key {} is malformed", kid))?;
+        keys.insert(kid.to_string(), key);
+    }
+
+    if let Ok(entries) = keys_dir.read_dir_utf8() {
+        for entry in entries {
+            let path = entry?.into_path();
+
+            if let (Some(kid), Some("pem")) = (path.file_stem(), path.extension()) {
+                let pem = std::fs::read_to_string(&path)?;
+                let key = RsaPublicKey::from_public_key_pem(&pem)
+                    .context(anyhow!("key {} is malformed", path))?;
+                keys.insert(kid.to_string(), key);
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Verifies a PS512 signature over the unencoded payload and returns the payload
+pub fn verify(data: &[u8], keys: &TrustedKeys) -> Result<String> {
+    if keys.is_empty() {
+        bail!(
+            "no trusted keys, put homebrew-1.pem from Homebrew/brew (Library/Homebrew/api) into {}",
+            SAMOGON_DATA_DIR.join("keys")
+        );
+    }
+
+    let jws: Jws = serde_json::from_slice(data).context("malformed JWS")?;
+
+    let mut kids = vec![];
+
+    for sig in &jws.signatures {
+        let protected: JwsHeader = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(&sig.protected)
+                .context("malformed JWS header")?,
+        )
+        .context("malformed JWS header")?;
+
+        let Some(kid) = protected
+            .kid
+            .as_ref()
+            .or(sig.header.as_ref().and_then(|h| h.kid.as_ref()))
+        else {
+            continue;
+        };
+
+        kids.push(kid.clone());
+
+        let Some(key) = keys.get(kid) else {
+            continue;
+        };
+
+        if protected.alg.as_deref() != Some("PS512") || protected.b64 != Some(false) {
+            bail!("signature {} must use PS512 over an unencoded payload", kid);
+        }
+
+        let signature = Signature::try_from(
+            URL_SAFE_NO_PAD
+                .decode(&sig.signature)
+                .context("malformed JWS signature")?
+                .as_slice(),
+        )?;
+
+        let message = format!("{}.{}", sig.protected, jws.payload);
+
+        VerifyingKey::<Sha512>::new(key.clone())
+            .verify(message.as_bytes(), &signature)
+            .map_err(|_| anyhow!("signature {} does not match the index", kid))?;
+
+        return Ok(jws.payload);
+    }
+
+    bail!(
+        "index is not signed by a trusted key (signed by: {}), put the key into {}",
+        if kids.is_empty() {
+            "nobody".to_string()
+        } else {
+            kids.join(", ")
+        },
+        SAMOGON_DATA_DIR.join("keys")
+    )
+}
+
+/// Signing with a locally generated key, for tests
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use rsa::pss::SigningKey;
+    use rsa::signature::{RandomizedSigner, SignatureEncoding};
+    use rsa::RsaPrivateKey;
+    use serde_json::json;
+    use std::sync::OnceLock;
+
+    pub fn private_key() -> &'static RsaPrivateKey {
+        static KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+
+        KEY.get_or_init(|| RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap())
+    }
+
+    pub fn keys(kid: &str) -> TrustedKeys {
+        HashMap::from([(kid.to_string(), private_key().to_public_key())])
+    }
+
+    /// A JWS over `signed` that carries `served` as its payload
+    pub fn sign(kid: &str, alg: &str, b64: bool, signed: &str, served: &str) -> Vec<u8> {
+        let header = json!({"alg": alg, "b64": b64, "crit": ["b64"], "kid": kid});
+        let protected = URL_SAFE_NO_PAD.encode(header.to_string());
+
+        let signature = SigningKey::<Sha512>::new(private_key().clone()).sign_with_rng(
+            &mut rand::thread_rng(),
+            format!("{}.{}", protected, signed).as_bytes(),
+        );
+
+        json!({
+            "payload": served,
+            "signatures": [{
+                "protected": protected,
+                "signature": URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            }],
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{keys, private_key, sign};
+    use super::*;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+
+    const PAYLOAD: &str = r#"[{"name": "liba"}]"#;
+
+    #[test]
+    fn embedded_keys_parse() {
+        let keys = load_keys(Utf8Path::new("/nonexistent")).unwrap();
+
+        for (kid, _) in EMBEDDED_KEYS {
+            assert!(keys.contains_key(*kid));
+        }
+    }
+
+    #[test]
+    fn valid_signature() {
+        let jws = sign("test-1", "PS512", false, PAYLOAD, PAYLOAD);
+
+        assert_eq!(verify(&jws, &keys("test-1")).unwrap(), PAYLOAD);
+    }
+
+    #[test]
+    fn tampered_payload() {
+        let jws = sign("test-1", "PS512", false, PAYLOAD, r#"[{"name": "evil"}]"#);
+
+        let err = verify(&jws, &keys("test-1")).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_kid() {
+        let jws = sign("test-2", "PS512", false, PAYLOAD, PAYLOAD);
+
+        let err = verify(&jws, &keys("test-1")).unwrap_err();
+        assert!(err.to_string().contains("signed by: test-2"), "{}", err);
+    }
+
+    #[test]
+    fn wrong_alg_or_b64() {
+        for jws in [
+            sign("test-1", "RS512", false, PAYLOAD, PAYLOAD),
+            sign("test-1", "PS512", true, PAYLOAD, PAYLOAD),
+        ] {
+            let err = verify(&jws, &keys("test-1")).unwrap_err();
+            assert!(err.to_string().contains("must use PS512"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rotated_key_from_keys_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys_dir = Utf8Path::from_path(dir.path()).unwrap();
+
+        let pem = private_key()
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        std::fs::write(keys_dir.join("rotated-2.pem"), pem).unwrap();
+        std::fs::write(keys_dir.join("notes.txt"), "not a key").unwrap();
+
+        let keys = load_keys(keys_dir).unwrap();
+        let jws = sign("rotated-2", "PS512", false, PAYLOAD, PAYLOAD);
+
+        assert_eq!(verify(&jws, &keys).unwrap(), PAYLOAD);
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+
+use crate::config::{HOMEBREW_CELLAR, HOMEBREW_PREFIX};
+
+/// Keg directories that are linked into the prefix
+const LINKED_DIRS: [&str; 6] = ["bin", "sbin", "etc", "include", "lib", "share"];
+/// Bottles built with these can be poured into any Cellar
+const RELOCATABLE_CELLARS: [&str; 2] = [":any", ":any_skip_relocation"];
+/// Files with a NUL byte this close to the start are taken for binaries
+const TEXT_PROBE_LEN: usize = 8192;
+
+/// Fails for a bottle built for another Cellar, which is baked into its binaries
+pub fn check_cellar(cellar: &str) -> Result<()> {
+    if RELOCATABLE_CELLARS.contains(&cellar) || Utf8Path::new(cellar) == *HOMEBREW_CELLAR {
+        return Ok(());
+    }
+
+    bail!(
+        "the bottle only works in the Cellar {}, not in {}",
+        cellar,
+        *HOMEBREW_CELLAR
+    )
+}
+
+/// Moves the keg of `name` unpacked into `staging` into the Cellar,
+/// replacing an existing keg of the same version
+pub fn pour(staging: &Utf8Path, name: &str) -> Result<Utf8PathBuf> {
+    let versions = staging.join(name);
+    let version = versions
+        .read_dir_utf8()
+        .context(anyhow!("the bottle contains no keg for {}", name))?
+        .next()
+        .context(anyhow!("the bottle contains no version of {}", name))??;
+
+    let keg = HOMEBREW_CELLAR.join(name).join(version.file_name());
+
+    if keg.exists() {
+        fs::remove_dir_all(&keg).context(anyhow!("while removing the old keg {}", keg))?;
+    }
+
+    fs::create_dir_all(keg.parent().unwrap())?;
+    fs::rename(version.path(), &keg).context(anyhow!("while moving the keg to {}", keg))?;
+
+    Ok(keg)
+}
+
+/// Fills in the `@@HOMEBREW_PREFIX@@` and friends that bottles are built with
+///
+/// TODO rewrite the placeholders in Mach-O load commands and ELF RPATHs too,
+/// only text files such as scripts and .pc files are handled
+pub fn relocate(keg: &Utf8Path) -> Result<()> {
+    let prefix = HOMEBREW_PREFIX.as_str();
+    let library = HOMEBREW_PREFIX.join("Library");
+
+    relocate_tree(
+        keg,
+        &[
+            ("@@HOMEBREW_PREFIX@@", prefix),
+            ("@@HOMEBREW_CELLAR@@", HOMEBREW_CELLAR.as_str()),
+            // samogon has no repository, Homebrew keeps it in the prefix by default
+            ("@@HOMEBREW_REPOSITORY@@", prefix),
+            ("@@HOMEBREW_LIBRARY@@", library.as_str()),
+        ],
+    )
+}
+
+fn relocate_tree(dir: &Utf8Path, placeholders: &[(&str, &str)]) -> Result<()> {
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            relocate_tree(entry.path(), placeholders)?;
+        } else if file_type.is_file() {
+            relocate_file(entry.path(), placeholders)
+                .context(anyhow!("while relocating {}", entry.path()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn relocate_file(path: &Utf8Path, placeholders: &[(&str, &str)]) -> Result<()> {
+    let data = fs::read(path)?;
+
+    if data[..data.len().min(TEXT_PROBE_LEN)].contains(&0) {
+        return Ok(());
+    }
+    let Ok(text) = String::from_utf8(data) else {
+        return Ok(());
+    };
+    if !text.contains("@@HOMEBREW_") {
+        return Ok(());
+    }
+
+    let text = placeholders
+        .iter()
+        .fold(text, |text, (placeholder, value)| {
+            text.replace(placeholder, value)
+        });
+
+    // Bottles ship read-only files
+    let perms = fs::metadata(path)?.permissions();
+    if perms.readonly() {
+        fs::set_permissions(path, fs::Permissions::from_mode(perms.mode() | 0o200))?;
+    }
+
+    fs::write(path, text)?;
+    fs::set_permissions(path, perms)?;
+
+    Ok(())
+}
+
+/// Points `opt/<name>` at the keg, keg-only formulae are reachable only through it
+pub fn link_opt(keg: &Utf8Path, name: &str) -> Result<()> {
+    let opt = HOMEBREW_PREFIX.join("opt");
+
+    fs::create_dir_all(&opt).context(anyhow!("while creating {}", opt))?;
+
+    replace_link(&opt.join(name), keg)
+}
+
+/// Symlinks the files of the keg into `bin`, `lib` etc. of the prefix
+pub fn link_prefix(keg: &Utf8Path, name: &str) -> Result<()> {
+    for dir in LINKED_DIRS {
+        let src = keg.join(dir);
+
+        if src.is_dir() {
+            link_tree(&src, &HOMEBREW_PREFIX.join(dir), name)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn link_tree(src: &Utf8Path, dst: &Utf8Path, name: &str) -> Result<()> {
+    fs::create_dir_all(dst).context(anyhow!("while creating {}", dst))?;
+
+    for entry in src.read_dir_utf8()? {
+        let entry = entry?;
+        let link = dst.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            link_tree(entry.path(), &link, name)?;
+            continue;
+        }
+
+        // Links into other versions of the same formula are ours to replace
+        let owned = link
+            .read_link_utf8()
+            .is_ok_and(|target| target.starts_with(HOMEBREW_CELLAR.join(name)));
+
+        if !owned && link.symlink_metadata().is_ok() {
+            bail!("{} already exists and does not belong to {}", link, name);
+        }
+
+        replace_link(&link, entry.path())?;
+    }
+
+    Ok(())
+}
+
+fn replace_link(link: &Utf8Path, target: &Utf8Path) -> Result<()> {
+    if link.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+        fs::remove_file(link)?;
+    }
+
+    symlink(target, link).context(anyhow!("while linking {} to {}", link, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relocatable_cellars() {
+        check_cellar(":any").unwrap();
+        check_cellar(":any_skip_relocation").unwrap();
+        check_cellar(HOMEBREW_CELLAR.as_str()).unwrap();
+        assert!(check_cellar("/home/linuxbrew/.linuxbrew/Cellar-elsewhere").is_err());
+    }
+
+    #[test]
+    fn placeholders_in_text_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let keg = Utf8Path::from_path(tmp.path()).unwrap();
+        let placeholders = [
+            ("@@HOMEBREW_PREFIX@@", "/prefix"),
+            ("@@HOMEBREW_CELLAR@@", "/prefix/Cellar"),
+        ];
+
+        fs::create_dir_all(keg.join("lib/pkgconfig")).unwrap();
+        let pc = keg.join("lib/pkgconfig/liba.pc");
+        fs::write(
+            &pc,
+            "prefix=@@HOMEBREW_CELLAR@@/liba/1.0\nbin=@@HOMEBREW_PREFIX@@/bin\n",
+        )
+        .unwrap();
+        fs::set_permissions(&pc, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let binary = keg.join("lib/liba.so");
+        let binary_data = b"\x7fELF\0\0@@HOMEBREW_PREFIX@@/lib".to_vec();
+        fs::write(&binary, &binary_data).unwrap();
+
+        relocate_tree(keg, &placeholders).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&pc).unwrap(),
+            "prefix=/prefix/Cellar/liba/1.0\nbin=/prefix/bin\n"
+        );
+        assert_eq!(
+            fs::metadata(&pc).unwrap().permissions().mode() & 0o777,
+            0o444
+        );
+        assert_eq!(fs::read(&binary).unwrap(), binary_data);
+    }
+}
@@ -0,0 +1,23 @@
+pub mod api;
+pub mod args;
+pub mod auth;
+pub mod config;
+pub mod database;
+pub mod deps;
+pub mod fetch_install;
+pub mod http;
+pub mod index;
+pub mod jws;
+pub mod keg;
+pub mod mirror;
+pub mod platform;
+pub mod registry;
+pub mod repo;
+pub mod retry;
+pub mod segmented;
+pub mod tap;
+#[cfg(test)]
+mod test_server;
+pub mod throttle;
+pub mod ui;
+pub mod util;
@@ -1,18 +1,11 @@
-mod args;
-mod config;
-mod database;
-mod deps;
-mod fetch_install;
-mod platform;
-mod repo;
-mod ui;
-mod util;
-
+use std::collections::HashMap;
 use std::process::exit;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
-use ui::confirm_install;
+use colored::Colorize;
+use samogon::ui::{confirm_install, print_info, print_search_results};
+use samogon::{args, database, deps, fetch_install, mirror, platform, repo, tap, throttle};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -21,21 +14,137 @@ async fn main() -> Result<()> {
     let args = args::Args::try_parse()?;
 
     match args.subcmd {
-        Some(args::Subcmd::Install { formulae }) => {
+        Some(args::Subcmd::Install {
+            formulae,
+            max_bandwidth,
+            max_host_connections,
+            force,
+        }) => {
+            throttle::init(max_bandwidth, max_host_connections);
+
             let repo = repo::get_repo().await?;
-            let deps = deps::find_deps(&formulae, &repo)?;
+            let platform = platform::get_current_platform();
+            let deps = deps::find_deps(&formulae, &repo, platform)?;
             let deps_formulae = deps
                 .iter()
-                .map(|d| repo.formulae[d].clone())
+                .map(|d| repo.formula(d))
+                .collect::<Result<Vec<_>>>()?;
+
+            // Before bottles: disabled formulae lose them, and the replacement is the better hint
+            let disabled = deps_formulae
+                .iter()
+                .filter_map(|f| Some((f, f.disabled.as_ref()?)))
                 .collect::<Vec<_>>();
 
-            if confirm_install(&deps_formulae).await? {
-                fetch_install::stream_all(deps_formulae).await?;
+            if !force && !disabled.is_empty() {
+                for (f, disabled) in &disabled {
+                    println!(
+                        "{} {} was {}",
+                        "!".bold(),
+                        f.name.bold(),
+                        disabled.describe("disabled")
+                    );
+                }
+                bail!("refusing to install disabled formulae, pass --force to install them anyway");
+            }
+
+            if let Some(f) = deps_formulae
+                .iter()
+                .find(|f| f.bottle_for(platform).is_none())
+            {
+                bail!(
+                    "{}: no bottle available for {}; building from source is not supported",
+                    f.name,
+                    platform
+                );
+            }
+
+            let installed = database::installed_kegs()?;
+            let conflicts = deps::find_conflicts(&deps_formulae, &installed, &repo)?;
+
+            if !conflicts.is_empty() {
+                for conflict in &conflicts {
+                    println!("{} {}", "!".bold(), conflict);
+                }
+                bail!("conflicting formulae, nothing was downloaded");
+            }
+
+            // Formulae requested by an alias or an old name, keyed by their real name
+            let requested_as = formulae
+                .iter()
+                .filter_map(|requested| {
+                    let name = repo.get(requested)?.full_name();
+                    let base = |n: &str| n.rsplit('/').next().unwrap_or_default().to_string();
+
+                    (base(requested) != base(&name)).then(|| (name, requested.clone()))
+                })
+                .collect::<HashMap<_, _>>();
+
+            if confirm_install(&deps_formulae, &requested_as).await? {
+                fetch_install::stream_all(deps_formulae, &repo).await?;
             } else {
                 println!("! aborted");
                 exit(1);
             }
         }
+        Some(args::Subcmd::Search { query }) => {
+            let repo = repo::get_repo().await?;
+
+            print_search_results(&repo.search(&query)?, platform::get_current_platform());
+        }
+        Some(args::Subcmd::Info { formula }) => {
+            let repo = repo::get_repo().await?;
+            let formula = repo.formula(&formula)?;
+
+            print_info(&formula, platform::get_current_platform());
+        }
+        Some(args::Subcmd::Deps { formula }) => {
+            let repo = repo::get_repo().await?;
+            let deps = deps::find_deps(
+                std::slice::from_ref(&formula),
+                &repo,
+                platform::get_current_platform(),
+            )?;
+
+            // The formula itself comes last
+            for dep in deps.split_last().map_or(&[][..], |(_, deps)| deps) {
+                println!("{}", dep);
+            }
+        }
+        Some(args::Subcmd::Update) => {
+            if repo::update_repo().await? {
+                println!(" -> updated the formula index");
+            } else {
+                println!(" -> the formula index is up to date");
+            }
+        }
+        Some(args::Subcmd::Tap { cmd }) => match cmd {
+            args::TapCmd::Add { name, url, prefer } => {
+                let count = tap::add_tap(&name, &url, prefer).await?;
+                println!(" -> added tap {} with {} formulae", name, count);
+            }
+            args::TapCmd::Remove { name } => {
+                tap::remove_tap(&name)?;
+                println!(" -> removed tap {}", name);
+            }
+            args::TapCmd::List => {
+                let taps = tap::read_taps()?;
+                let (preferred, others): (Vec<_>, Vec<_>) =
+                    taps.into_iter().partition(|t| t.preferred);
+
+                for t in &preferred {
+                    println!("{} {} (preferred)", t.name.bold(), t.url);
+                }
+                println!(
+                    "{} {}",
+                    "homebrew/core".bold(),
+                    mirror::api_url("formula.jws.json")
+                );
+                for t in &others {
+                    println!("{} {}", t.name.bold(), t.url);
+                }
+            }
+        },
         None => {
             return Ok(());
         }
@@ -0,0 +1,64 @@
+use reqwest::Url;
+use std::collections::HashSet;
+
+use crate::config::{API_MIRRORS, ARTIFACT_MIRRORS, BOTTLE_MIRRORS};
+
+pub const DEFAULT_API_DOMAIN: &str = "https://formulae.brew.sh/api";
+pub const DEFAULT_BOTTLE_DOMAIN: &str = "https://ghcr.io/v2/homebrew/core";
+
+/// The canonical URL of an API file
+pub fn api_url(file: &str) -> String {
+    format!("{}/{}", DEFAULT_API_DOMAIN, file)
+}
+
+/// URLs to try for an API file, mirrors first and the origin last
+pub fn api_urls(file: &str) -> Vec<String> {
+    let mut urls = API_MIRRORS
+        .iter()
+        .map(|m| format!("{}/{}", m.trim_end_matches('/'), file))
+        .collect::<Vec<_>>();
+
+    urls.push(api_url(file));
+
+    dedup(urls)
+}
+
+/// URLs to try for a bottle, mirrors first and the origin last
+///
+/// Artifact mirrors replace the whole domain, bottle mirrors only stand in for
+/// the default bottle domain
+pub fn bottle_urls(url: &str) -> Vec<String> {
+    let mut urls = vec![];
+
+    if let Ok(parsed) = Url::parse(url) {
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{}", parsed.path(), query),
+            None => parsed.path().to_string(),
+        };
+
+        urls.extend(
+            ARTIFACT_MIRRORS
+                .iter()
+                .map(|m| format!("{}{}", m.trim_end_matches('/'), path)),
+        );
+    }
+
+    if let Some(rest) = url.strip_prefix(DEFAULT_BOTTLE_DOMAIN) {
+        urls.extend(
+            BOTTLE_MIRRORS
+                .iter()
+                .map(|m| format!("{}{}", m.trim_end_matches('/'), rest)),
+        );
+    }
+
+    urls.push(url.to_string());
+
+    dedup(urls)
+}
+
+fn dedup(mut urls: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    urls.retain(|u| seen.insert(u.clone()));
+
+    urls
+}
@@ -2,6 +2,49 @@ use std::sync::OnceLock;
 
 static PLATFORM: OnceLock<String> = OnceLock::new();
 
+/// macOS codenames in release order, as they appear in bottle tags
+const MACOS_VERSIONS: &[&str] = &[
+    "yosemite",
+    "el_capitan",
+    "sierra",
+    "high_sierra",
+    "mojave",
+    "catalina",
+    "big_sur",
+    "monterey",
+    "ventura",
+    "sonoma",
+    "sequoia",
+    "tahoe",
+];
+
+pub fn is_linux(platform: &str) -> bool {
+    platform.ends_with("_linux")
+}
+
+/// Position of the macOS release targeted by a bottle tag or a `since:` bound
+pub fn macos_version_ord(platform: &str) -> Option<usize> {
+    let codename = platform.strip_prefix("arm64_").unwrap_or(platform);
+
+    MACOS_VERSIONS.iter().position(|v| *v == codename)
+}
+
+/// Whether a `uses_from_macos` dependency has to be installed on the given platform
+pub fn macos_dep_applies(since: Option<&str>, platform: &str) -> bool {
+    if is_linux(platform) {
+        return true;
+    }
+
+    match (since, macos_version_ord(platform)) {
+        // A release newer than we know of is newer than the running one
+        (Some(since), Some(current)) => match macos_version_ord(since) {
+            Some(since) => current < since,
+            None => true,
+        },
+        _ => false,
+    }
+}
+
 pub fn get_current_platform() -> &'static str {
     PLATFORM.get_or_init(|| {
         let os = os_info::get();
@@ -10,6 +53,8 @@ pub fn get_current_platform() -> &'static str {
             os_info::Type::Macos => {
                 let version = match os.version() {
                     os_info::Version::Semantic(maj, min, _) => match maj {
+                        26 => "tahoe",
+                        15 => "sequoia",
                         14 => "sonoma",
                         13 => "ventura",
                         12 => "monterey",
@@ -34,7 +79,30 @@ pub fn get_current_platform() -> &'static str {
                     _ => panic!("Unsupported architecture"),
                 }
             }
-            _ => panic!("Only macOS is supported"),
+            _ if cfg!(target_os = "linux") => match std::env::consts::ARCH {
+                "x86_64" => "x86_64_linux".to_string(),
+                "aarch64" => "arm64_linux".to_string(),
+                _ => panic!("Unsupported architecture"),
+            },
+            _ => panic!("Only macOS and Linux are supported"),
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn since_bounds() {
+        assert!(macos_dep_applies(Some("sequoia"), "arm64_sonoma"));
+        assert!(!macos_dep_applies(Some("sequoia"), "arm64_sequoia"));
+        assert!(!macos_dep_applies(None, "ventura"));
+        assert!(macos_dep_applies(None, "x86_64_linux"));
+    }
+
+    #[test]
+    fn unknown_since_applies() {
+        assert!(macos_dep_applies(Some("some_future_release"), "sonoma"));
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use reqwest::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{
+    DOCKER_REGISTRY_BASIC_AUTH_TOKEN, DOCKER_REGISTRY_TOKEN, GITHUB_PACKAGES_TOKEN,
+    GITHUB_PACKAGES_USER,
+};
+
+/// Lifetime of a token when the registry does not say, per the distribution spec
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+/// Token endpoint of GitHub Packages, which `HOMEBREW_GITHUB_PACKAGES_TOKEN` is for
+const GHCR_TOKEN_REALM: &str = "https://ghcr.io/token";
+
+struct CachedToken {
+    token: String,
+    expires: Instant,
+}
+
+lazy_static! {
+    /// Pull tokens by registry host and repository
+    static ref TOKENS: Mutex<HashMap<String, CachedToken>> = Mutex::new(HashMap::new());
+}
+
+/// A parsed `WWW-Authenticate: Bearer realm="...",service="...",scope="..."`
+#[derive(Debug)]
+struct Challenge {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    let params = header.strip_prefix("Bearer ")?;
+
+    let mut realm = None;
+    let mut service = None;
+    let mut scope = None;
+
+    // Values are quoted and may contain commas, e.g. scope="repository:a:pull,push"
+    let mut rest = params.trim();
+    while let Some((key, tail)) = rest.split_once('=') {
+        let tail = tail.strip_prefix('"')?;
+        let (value, tail) = tail.split_once('"')?;
+
+        match key.trim() {
+            "realm" => realm = Some(value.to_string()),
+            "service" => service = Some(value.to_string()),
+            "scope" => scope = Some(value.to_string()),
+            _ => {}
+        }
+
+        rest = tail.trim_start_matches(',').trim();
+    }
+
+    Some(Challenge {
+        realm: realm?,
+        service,
+        scope,
+    })
+}
+
+/// Tokens are scoped to a repository, e.g. `ghcr.io/homebrew/core/wget`
+fn token_key(url: &Url) -> String {
+    let path = url.path();
+    let repo = path
+        .strip_prefix("/v2/")
+        .and_then(|p| p.split_once("/blobs/").or(p.split_once("/manifests/")))
+        .map_or("", |(repo, _)| repo);
+
+    format!("{}/{}", url.host_str().unwrap_or_default(), repo)
+}
+
+fn cached_token(key: &str) -> Option<String> {
+    let tokens = TOKENS.lock().unwrap();
+
+    tokens
+        .get(key)
+        .filter(|t| t.expires > Instant::now())
+        .map(|t| t.token.clone())
+}
+
+/// The realm comes from the registry's response, so the GitHub token only goes
+/// over HTTPS to the registry itself or to GitHub Packages
+fn may_send_credentials(registry: &Url, realm: &str) -> bool {
+    let Ok(realm) = Url::parse(realm) else {
+        return false;
+    };
+
+    realm.scheme() == "https"
+        && (realm.host_str() == registry.host_str()
+            || realm.as_str().trim_end_matches('/') == GHCR_TOKEN_REALM)
+}
+
+async fn fetch_token(
+    client: &Client,
+    registry: &Url,
+    challenge: &Challenge,
+) -> Result<(String, Duration)> {
+    let mut query = vec![];
+    if let Some(service) = &challenge.service {
+        query.push(("service", service.as_str()));
+    }
+    if let Some(scope) = &challenge.scope {
+        query.push(("scope", scope.as_str()));
+    }
+
+    let mut req = client.get(&challenge.realm).query(&query);
+
+    // Without credentials the registry hands out an anonymous pull token
+    if let Some(token) = GITHUB_PACKAGES_TOKEN
+        .as_ref()
+        .filter(|_| may_send_credentials(registry, &challenge.realm))
+    {
+        req = req.basic_auth(
+            GITHUB_PACKAGES_USER.as_deref().unwrap_or("token"),
+            Some(token),
+        );
+    }
+
+    let resp: TokenResponse = req
+        .send()
+        .await?
+        .error_for_status()
+        .context("token request was rejected")?
+        .json()
+        .await
+        .context("malformed token response")?;
+
+    let token = resp
+        .token
+        .or(resp.access_token)
+        .context("no token in the token response")?;
+
+    let lifetime = resp
+        .expires_in
+        .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+
+    Ok((token, lifetime))
+}
+
+/// Sends a GET to a container registry, going through the token exchange on a 401
+///
+/// `build` is applied to every attempt, e.g. to add a `Range` header
+pub async fn registry_get(
+    client: &Client,
+    url: &str,
+    build: impl Fn(RequestBuilder) -> RequestBuilder,
+) -> Result<Response> {
+    // Credentials for a registry proxying GitHub Packages are used as is
+    if let Some(token) = &*DOCKER_REGISTRY_TOKEN {
+        return Ok(build(client.get(url)).bearer_auth(token).send().await?);
+    }
+    if let Some(basic) = &*DOCKER_REGISTRY_BASIC_AUTH_TOKEN {
+        return Ok(build(client.get(url))
+            .header(AUTHORIZATION, format!("Basic {}", basic))
+            .send()
+            .await?);
+    }
+
+    let registry = Url::parse(url)?;
+    let key = token_key(&registry);
+
+    let mut req = build(client.get(url));
+    if let Some(token) = cached_token(&key) {
+        req = req.bearer_auth(token);
+    }
+
+    let resp = req.send().await?;
+
+    if resp.status() != StatusCode::UNAUTHORIZED {
+        return Ok(resp);
+    }
+
+    let challenge = resp
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(parse_challenge)
+        .context(anyhow!("{} requires authentication we do not support", url))?;
+
+    let (token, lifetime) = fetch_token(client, &registry, &challenge)
+        .await
+        .context(anyhow!(
+            "while getting a pull token from {}",
+            challenge.realm
+        ))?;
+
+    TOKENS.lock().unwrap().insert(
+        key,
+        CachedToken {
+            token: token.clone(),
+            expires: Instant::now() + lifetime,
+        },
+    );
+
+    Ok(build(client.get(url)).bearer_auth(token).send().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_stay_with_the_registry() {
+        let ghcr = Url::parse("https://ghcr.io/v2/homebrew/core/wget/blobs/sha256:00").unwrap();
+        let mirror =
+            Url::parse("https://mirror.example/v2/homebrew/core/wget/blobs/sha256:00").unwrap();
+
+        assert!(may_send_credentials(&ghcr, "https://ghcr.io/token"));
+        assert!(may_send_credentials(&mirror, "https://mirror.example/auth"));
+        assert!(may_send_credentials(&mirror, "https://ghcr.io/token"));
+        assert!(!may_send_credentials(&mirror, "https://evil.example/token"));
+        assert!(!may_send_credentials(&ghcr, "http://ghcr.io/token"));
+        assert!(!may_send_credentials(&ghcr, "not a url"));
+    }
+}
@@ -1,16 +1,50 @@
-use anyhow::{anyhow, bail, Context, Result};
-use camino::Utf8Path;
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
 use indicatif::ProgressBar;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
-use tokio::fs::{create_dir_all, write};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Cursor, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::fs::create_dir_all;
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
 
-use crate::{config::SAMOGON_DATA_DIR, ui::fetch_bar_style};
+use crate::{
+    api::parse_formulae,
+    config::{INDEX_TTL, SAMOGON_DATA_DIR},
+    http::{client, idle_timeout},
+    index::{build_payload, write_index, FormulaRef, Index, IndexErr, IndexHeader},
+    jws::{self, TrustedKeys},
+    mirror::{api_url, api_urls},
+    platform::macos_dep_applies,
+    tap::{self, TapSource},
+    ui::fetch_bar_style,
+    util::edit_distance,
+};
 
-const FORMULAE_URL: &str = "https://formulae.brew.sh/api/formula.json";
+const FORMULAE_FILE: &str = "formula.jws.json";
+const MAX_SUGGESTIONS: usize = 3;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Error, Debug)]
+#[error("No formula named {name}{}", did_you_mean(.suggestions))]
+pub struct UnknownFormula {
+    pub name: String,
+    pub suggestions: Vec<String>,
+}
+
+fn did_you_mean(suggestions: &[String]) -> String {
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!(", did you mean {}?", suggestions.join(" or "))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct FormulaStable {
     pub name: String,
     pub description: String,
@@ -21,12 +55,98 @@ pub struct FormulaStable {
     pub deps: Vec<String>,
     pub opt_deps: Vec<String>,
     pub rec_deps: Vec<String>,
+    pub uses_from_macos: Vec<MacosDep>,
+    pub aliases: Vec<String>,
+    /// Names the formula had before being renamed
+    pub oldnames: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+    /// Why the formula is not linked into the prefix, if it is keg-only
+    pub keg_only: Option<String>,
+    /// Notes for the user, with the prefix and the Cellar filled in
+    pub caveats: Option<String>,
+    pub deprecated: Option<Deprecation>,
+    /// Disabled formulae are only installed with `--force`
+    pub disabled: Option<Deprecation>,
 }
 
 impl FormulaStable {
+    /// The name of the formula's directory in the Cellar and in `opt`
+    pub fn keg_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+
     pub fn version_fmt(&self) -> String {
         format!("{}_{}", self.version, self.revision)
     }
+
+    pub fn is_bottled(&self) -> bool {
+        !self.bottles.is_empty()
+    }
+
+    /// The bottle for the given platform, falling back to a platform-independent one
+    pub fn bottle_for(&self, platform: &str) -> Option<&Bottle> {
+        self.bottles
+            .get(platform)
+            .or_else(|| self.bottles.get("all"))
+    }
+
+    /// Runtime dependencies on the given platform, including the
+    /// `uses_from_macos` ones which the system does not provide there
+    pub fn platform_deps<'a>(&'a self, platform: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.deps.iter().map(String::as_str).chain(
+            self.uses_from_macos
+                .iter()
+                .filter(move |d| d.applies_to(platform))
+                .map(|d| d.name.as_str()),
+        )
+    }
+}
+
+/// A formula that cannot be installed alongside this one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Conflict {
+    pub name: String,
+    pub reason: Option<String>,
+}
+
+/// Why and since when a formula is deprecated or disabled
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Deprecation {
+    pub date: Option<String>,
+    pub reason: Option<String>,
+    pub replacement: Option<String>,
+}
+
+impl Deprecation {
+    /// e.g. "deprecated since 2024-01-01 (unmaintained), use bar instead"
+    pub fn describe(&self, what: &str) -> String {
+        let mut text = what.to_string();
+
+        if let Some(date) = &self.date {
+            text += &format!(" since {}", date);
+        }
+        if let Some(reason) = &self.reason {
+            text += &format!(" ({})", reason.replace('_', " "));
+        }
+        if let Some(replacement) = &self.replacement {
+            text += &format!(", use {} instead", replacement);
+        }
+
+        text
+    }
+}
+
+/// A dependency that macOS ships with the system, optionally only since some release
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MacosDep {
+    pub name: String,
+    pub since: Option<String>,
+}
+
+impl MacosDep {
+    pub fn applies_to(&self, platform: &str) -> bool {
+        macos_dep_applies(self.since.as_deref(), platform)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,136 +156,735 @@ pub struct Bottle {
     pub sha256: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// An index from a tap, its formulae are named `<tap>/<formula>`
+struct Tap {
+    name: String,
+    preferred: bool,
+    index: Index,
+}
+
 pub struct Repo {
-    pub formulae: HashMap<String, FormulaStable>,
+    index: Index,
+    taps: Vec<Tap>,
 }
 
-async fn read_index_cached(path: &Utf8Path) -> Result<Repo> {
-    if tokio::fs::try_exists(path).await? {
-        // TODO maybe do it with a reader
-        let data = tokio::fs::read(path).await?;
+impl Repo {
+    /// Indexes in the order bare names are resolved in: preferred taps, core,
+    /// then the other taps in the order they were added
+    fn indexes(&self) -> impl Iterator<Item = &Index> {
+        let preferred = self.taps.iter().filter(|t| t.preferred);
+        let others = self.taps.iter().filter(|t| !t.preferred);
 
-        bincode::deserialize(&data).map_err(|e| anyhow!("deserialization error: {}", e))
-    } else {
-        bail!("index path does not exist")
+        preferred
+            .map(|t| &t.index)
+            .chain(std::iter::once(&self.index))
+            .chain(others.map(|t| &t.index))
+    }
+
+    /// Looks a formula up by name, alias or old name
+    ///
+    /// Fully qualified names always win over the resolution order of bare
+    /// ones, and real names over aliases
+    pub fn get(&self, name: &str) -> Option<FormulaRef<'_>> {
+        if let Some(name) = name.strip_prefix("homebrew/core/") {
+            return self.index.get(name).or_else(|| self.index.get_alias(name));
+        }
+
+        if let Some((tap, name)) = name.split_once('/') {
+            let index = &self.taps.iter().find(|t| t.name == tap)?.index;
+            return index.get(name).or_else(|| index.get_alias(name));
+        }
+
+        self.indexes()
+            .find_map(|index| index.get(name))
+            .or_else(|| self.indexes().find_map(|index| index.get_alias(name)))
+    }
+
+    /// Like `get`, but suggests similar names when there is no such formula
+    pub fn resolve(&self, name: &str) -> Result<FormulaRef<'_>, UnknownFormula> {
+        self.get(name).ok_or_else(|| UnknownFormula {
+            name: name.to_string(),
+            suggestions: self.suggest(name),
+        })
+    }
+
+    /// Closest formula names to a misspelled one, matching aliases too
+    fn suggest(&self, name: &str) -> Vec<String> {
+        let bare = name.rsplit('/').next().unwrap_or(name).to_lowercase();
+        let max_distance = (bare.chars().count() / 3).max(1);
+
+        let names = self
+            .indexes()
+            .flat_map(|index| index.iter().map(|f| (f.name(), f)));
+        let aliases = self.indexes().flat_map(|index| index.aliases());
+
+        let mut close = names
+            .chain(aliases)
+            .map(|(candidate, f)| (edit_distance(&bare, &candidate.to_lowercase()), f))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .map(|(distance, f)| (distance, f.full_name()))
+            .collect::<Vec<_>>();
+        close.sort();
+
+        let mut suggestions = Vec::new();
+        for (_, candidate) in close {
+            if !suggestions.contains(&candidate) {
+                suggestions.push(candidate);
+            }
+        }
+        suggestions.truncate(MAX_SUGGESTIONS);
+
+        suggestions
+    }
+
+    pub fn formula(&self, name: &str) -> Result<FormulaStable> {
+        self.resolve(name)?.load()
+    }
+
+    /// Case-insensitive search by name and description, name prefix matches first
+    pub fn search(&self, query: &str) -> Result<Vec<FormulaStable>> {
+        let query = query.to_lowercase();
+
+        let mut found = self
+            .indexes()
+            .flat_map(|index| index.with_prefix(&query))
+            .collect::<Vec<_>>();
+
+        found.extend(self.indexes().flat_map(|index| index.iter()).filter(|f| {
+            !f.name().starts_with(&query)
+                && (f.name().to_lowercase().contains(&query)
+                    || f.description().to_lowercase().contains(&query))
+        }));
+
+        found.iter().map(FormulaRef::load).collect()
+    }
+}
+
+impl IndexHeader {
+    fn is_stale(&self) -> bool {
+        unix_now().saturating_sub(self.fetched_at) >= INDEX_TTL.as_secs()
+    }
+}
+
+enum Fetched {
+    NotModified,
+    Updated(Vec<FormulaStable>, IndexHeader),
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Fetches the index from the first mirror that works, falling back to the origin
+async fn fetch_repo(
+    urls: &[String],
+    keys: &TrustedKeys,
+    cached: Option<&IndexHeader>,
+) -> Result<Fetched> {
+    let mut last_err = None;
+
+    for (i, url) in urls.iter().enumerate() {
+        match fetch_repo_from(url, keys, cached).await {
+            Ok(fetched) => return Ok(fetched),
+            Err(e) => {
+                if i + 1 < urls.len() {
+                    println!(
+                        "{} {} failed, trying the next mirror: {}",
+                        "!".bold(),
+                        url,
+                        e
+                    );
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+
+    Err(last_err.unwrap())
+}
+
+/// Hands response chunks from the async side over to a blocking parser
+struct ChunkReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
     }
 }
 
-async fn fetch_repo() -> Result<Repo> {
+/// Downloads an index and feeds it to `parse` while it arrives, unless the
+/// server reports that `cached` is still current; `file://` URLs are read as is
+async fn download<T: Send + 'static>(
+    what: &str,
+    url: &str,
+    source_url: &str,
+    cached: Option<&IndexHeader>,
+    parse: impl FnOnce(&mut dyn Read) -> Result<T> + Send + 'static,
+) -> Result<Option<(T, IndexHeader)>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        let file = std::fs::File::open(path).context(anyhow!("while reading {}", path))?;
+        let parsed = spawn_blocking(move || parse(&mut BufReader::new(file))).await??;
+
+        let header = IndexHeader {
+            source_url: source_url.to_string(),
+            fetched_at: unix_now(),
+            etag: None,
+            last_modified: None,
+        };
+
+        return Ok(Some((parsed, header)));
+    }
+
     let progress = ProgressBar::new(0)
         .with_style(fetch_bar_style())
-        .with_prefix(" -> fetching repo index --")
+        .with_prefix(format!(" -> fetching {} --", what))
         .with_message("opening connection...");
 
-    let mut resp = reqwest::get(FORMULAE_URL).await?;
-
-    progress.set_length(
-        resp.content_length()
-            .context("no content length received")?,
-    );
-
-    let mut data: Vec<u8> = vec![];
-
-    while let Some(chunk) = resp.chunk().await? {
-        progress.inc(chunk.len() as _);
-        data.extend(chunk);
-    }
-
-    let val = serde_json::from_slice(&data)?;
-
-    parse_json_repo(&val)
-}
-
-fn parse_json_repo(val: &Value) -> Result<Repo> {
-    // TODO Fix this abomination
-    Ok(Repo {
-        formulae: val
-            .as_array()
-            .context("Did not find top-level array in API answer")?
-            .iter()
-            .map(|form| {
-                let name = form["name"].as_str().context("Package name not found")?;
-
-                let formula = FormulaStable {
-                    name: name.to_string(),
-                    description: form["desc"]
-                        .as_str()
-                        .context("Package description not found")?
-                        .to_string(),
-                    version: form["versions"]["stable"]
-                        .as_str()
-                        .context("Stable package version not found")?
-                        .to_string(),
-                    revision: form["revision"].as_i64().unwrap_or(0),
-                    deps: form["dependencies"]
-                        .as_array()
-                        .context("Dependency array not found")?
-                        .iter()
-                        .map(|v| {
-                            Ok(v.as_str()
-                                .context("Dependency is not a string")?
-                                .to_string())
-                        })
-                        .collect::<Result<Vec<_>>>()?,
-                    opt_deps: form["optional_dependencies"]
-                        .as_array()
-                        .context("Optional dependency array not found")?
-                        .iter()
-                        .map(|v| {
-                            Ok(v.as_str()
-                                .context("Dependency is not a string")?
-                                .to_string())
-                        })
-                        .collect::<Result<Vec<_>>>()?,
-                    rec_deps: form["recommended_dependencies"]
-                        .as_array()
-                        .context("Recommended dependency array not found")?
-                        .iter()
-                        .map(|v| {
-                            Ok(v.as_str()
-                                .context("Dependency is not a string")?
-                                .to_string())
-                        })
-                        .collect::<Result<Vec<_>>>()?,
-                    bottles: form["bottle"]["stable"]["files"]
-                        .as_object()
-                        .context("Bottle files not found")?
-                        .iter()
-                        .map(|(n, v)| {
-                            Ok((
-                                n.clone(),
-                                serde_json::from_value::<Bottle>(v.clone())
-                                    .context("Failed to parse bottle file value")?,
-                            ))
-                        })
-                        .collect::<Result<HashMap<_, _>>>()
-                        .context("Failed to parse bottle list")?,
-                };
-
-                Ok((name.to_string(), formula))
-            })
-            .collect::<Result<HashMap<_, _>>>()
-            .context("Failed to parse formulae")?,
-    })
+    let mut req = client()?.get(url);
+
+    if let Some(header) = cached {
+        if let Some(etag) = &header.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &header.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let mut resp = idle_timeout(req.send()).await?.error_for_status()?;
+
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        progress.finish_and_clear();
+        return Ok(None);
+    }
+
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+
+    let index_header = IndexHeader {
+        source_url: source_url.to_string(),
+        fetched_at: unix_now(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+
+    if let Some(len) = resp.content_length() {
+        progress.set_length(len);
+    }
+
+    let (tx, rx) = mpsc::channel(16);
+    let parser = spawn_blocking(move || {
+        parse(&mut BufReader::new(ChunkReader {
+            rx,
+            chunk: Cursor::default(),
+        }))
+    });
+
+    let received = async {
+        while let Some(chunk) = idle_timeout(resp.chunk()).await? {
+            progress.inc(chunk.len() as _);
+
+            // The parser has failed, its error is reported instead
+            if tx.send(chunk.to_vec()).await.is_err() {
+                break;
+            }
+        }
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    drop(tx);
+    let parsed = parser.await?;
+    // A broken connection also makes the parser fail, but that error says less
+    received?;
+
+    Ok(Some((parsed?, index_header)))
 }
 
+/// Fetches the core index unless the server reports that `cached` is still current
+async fn fetch_repo_from(
+    url: &str,
+    keys: &TrustedKeys,
+    cached: Option<&IndexHeader>,
+) -> Result<Fetched> {
+    let keys = keys.clone();
+
+    // The signature covers the whole payload and its header comes after it, so
+    // the signed index is buffered and verified before anything is parsed
+    let verify_and_parse = move |body: &mut dyn Read| {
+        let mut data = vec![];
+        body.read_to_end(&mut data)?;
+
+        let payload = jws::verify(&data, &keys)
+            .context("refusing to use an index that failed verification")?;
+
+        parse_formulae(payload.as_bytes())
+    };
+
+    let Some(((formulae, warnings), header)) = download(
+        "repo index",
+        url,
+        &api_url(FORMULAE_FILE),
+        cached,
+        verify_and_parse,
+    )
+    .await?
+    else {
+        return Ok(Fetched::NotModified);
+    };
+
+    for w in warnings {
+        println!("{} {}", "!".bold(), w);
+    }
+
+    Ok(Fetched::Updated(formulae, header))
+}
+
+/// Fetches an unsigned tap index
+async fn fetch_tap(tap: &TapSource, cached: Option<&IndexHeader>) -> Result<Fetched> {
+    let what = format!("tap {}", tap.name);
+
+    let Some(((formulae, warnings), header)) =
+        download(&what, &tap.url, &tap.url, cached, |body| {
+            parse_formulae(body)
+        })
+        .await?
+    else {
+        return Ok(Fetched::NotModified);
+    };
+
+    for w in warnings {
+        println!("{} {}: {}", "!".bold(), what, w);
+    }
+
+    Ok(Fetched::Updated(
+        qualify_tap_deps(&tap.name, formulae),
+        header,
+    ))
+}
+
+/// Points bare dependencies and conflicts on formulae of the same tap at the
+/// tap, so that they do not resolve to core
+fn qualify_tap_deps(tap: &str, mut formulae: Vec<FormulaStable>) -> Vec<FormulaStable> {
+    let names = formulae
+        .iter()
+        .map(|f| f.name.clone())
+        .collect::<HashSet<_>>();
+
+    for f in &mut formulae {
+        for dep in f
+            .deps
+            .iter_mut()
+            .chain(&mut f.opt_deps)
+            .chain(&mut f.rec_deps)
+            .chain(f.uses_from_macos.iter_mut().map(|d| &mut d.name))
+            .chain(f.conflicts.iter_mut().map(|c| &mut c.name))
+        {
+            if names.contains(dep) {
+                *dep = format!("{}/{}", tap, dep);
+            }
+        }
+    }
+
+    formulae
+}
+
+/// Where an index comes from
+enum Source<'a> {
+    /// Mirrors in the order they are tried, and the keys the index must be signed with
+    Core {
+        urls: Vec<String>,
+        keys: TrustedKeys,
+    },
+    Tap(&'a TapSource),
+}
+
+impl Source<'_> {
+    fn core() -> Result<Source<'static>> {
+        Ok(Source::Core {
+            urls: api_urls(FORMULAE_FILE),
+            keys: jws::trusted_keys()?,
+        })
+    }
+
+    fn source_url(&self) -> String {
+        match self {
+            // Mirrors serve the same index, so the cache is keyed by the origin
+            Source::Core { .. } => api_url(FORMULAE_FILE),
+            Source::Tap(tap) => tap.url.clone(),
+        }
+    }
+
+    fn index_path(&self) -> Utf8PathBuf {
+        match self {
+            Source::Core { .. } => SAMOGON_DATA_DIR.join("index.bin"),
+            Source::Tap(tap) => tap::index_path(&tap.name),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Source::Core { .. } => "the index".to_string(),
+            Source::Tap(tap) => format!("tap {}", tap.name),
+        }
+    }
+
+    async fn fetch(&self, cached: Option<&IndexHeader>) -> Result<Fetched> {
+        match self {
+            Source::Core { urls, keys } => fetch_repo(urls, keys, cached).await,
+            Source::Tap(tap) => fetch_tap(tap, cached).await,
+        }
+    }
+}
+
+/// Returns the cached index, refreshing it first if it is older than the TTL
 pub async fn get_repo() -> Result<Repo> {
-    create_dir_all(&*SAMOGON_DATA_DIR).await?;
+    Ok(load_repo(false).await?.0)
+}
 
-    let index_path = SAMOGON_DATA_DIR.join("index.bin");
+/// Refreshes the index regardless of its age, returns whether it has changed
+pub async fn update_repo() -> Result<bool> {
+    Ok(load_repo(true).await?.1)
+}
 
-    if let Ok(cached_index) = read_index_cached(&index_path).await {
-        Ok(cached_index)
-    } else {
-        let repo = fetch_repo().await?;
+async fn load_repo(force_refresh: bool) -> Result<(Repo, bool)> {
+    let (index, mut updated) = load_index(&Source::core()?, force_refresh).await?;
+
+    let mut taps = vec![];
 
-        if let Ok(bin) = bincode::serialize(&repo) {
-            let _ = write(index_path, &bin).await;
-            // else warn
+    for source in tap::read_taps()? {
+        match load_index(&Source::Tap(&source), force_refresh).await {
+            Ok((mut index, tap_updated)) => {
+                index.namespace = Some(source.name.clone());
+                updated |= tap_updated;
+
+                taps.push(Tap {
+                    name: source.name,
+                    preferred: source.preferred,
+                    index,
+                });
+            }
+            Err(e) => println!("{} skipping tap {}: {:#}", "!".bold(), source.name, e),
         }
-        // else warn
+    }
+
+    Ok((Repo { index, taps }, updated))
+}
+
+/// Fetches a tap regardless of its cache and returns the number of its formulae
+pub async fn fetch_tap_index(source: &TapSource) -> Result<usize> {
+    Ok(load_index(&Source::Tap(source), true).await?.0.len())
+}
+
+async fn load_index(source: &Source<'_>, force_refresh: bool) -> Result<(Index, bool)> {
+    let index_path = source.index_path();
+
+    if let Some(dir) = index_path.parent() {
+        create_dir_all(dir).await?;
+    }
+
+    let cached = match Index::open(&index_path, &source.source_url()) {
+        Ok(index) => Some(index),
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<IndexErr>() {
+                println!(
+                    "{} rebuilding the cache of {}: {}",
+                    "!".bold(),
+                    source.describe(),
+                    e
+                );
+            }
+            None
+        }
+    };
+
+    match cached {
+        Some(index) if !force_refresh && !index.header.is_stale() => Ok((index, false)),
+        cached => {
+            // A 304 would keep the payload, so it has to be intact
+            let cached = cached.filter(|index| match index.verify_checksum() {
+                Ok(()) => true,
+                Err(e) => {
+                    println!(
+                        "{} rebuilding the cache of {}: {}",
+                        "!".bold(),
+                        source.describe(),
+                        e
+                    );
+                    false
+                }
+            });
+
+            refresh_index(source, &index_path, cached, force_refresh).await
+        }
+    }
+}
+
+/// Writes the index cache, warning on failure since the index itself is still usable
+async fn save_index(
+    source: &Source<'_>,
+    index_path: &Utf8Path,
+    header: &IndexHeader,
+    payload: &[u8],
+) {
+    if let Err(e) = write_index(index_path, header, payload).await {
+        println!(
+            "{} could not cache {}: {:#}",
+            "!".bold(),
+            source.describe(),
+            e
+        );
+    }
+}
+
+async fn refresh_index(
+    source: &Source<'_>,
+    index_path: &Utf8Path,
+    cached: Option<Index>,
+    force_refresh: bool,
+) -> Result<(Index, bool)> {
+    match source.fetch(cached.as_ref().map(|i| &i.header)).await {
+        Ok(Fetched::NotModified) => {
+            let mut index =
+                cached.context("server reported an unchanged index that is not cached")?;
+
+            index.header.fetched_at = unix_now();
+            save_index(source, index_path, &index.header, index.payload()).await;
+
+            Ok((index, false))
+        }
+        Ok(Fetched::Updated(formulae, header)) => {
+            let payload = build_payload(&formulae)?;
+
+            save_index(source, index_path, &header, &payload).await;
+
+            Ok((Index::from_payload(header, payload)?, true))
+        }
+        Err(e) => match cached {
+            Some(index) if !force_refresh => {
+                println!(
+                    "{} could not refresh {}, using the cached one: {}",
+                    "!".bold(),
+                    source.describe(),
+                    e
+                );
+                Ok((index, false))
+            }
+            _ => Err(e.context(anyhow!("while fetching {}", source.describe()))),
+        },
+    }
+}
+
+/// In-memory repos, for tests
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// A repo of in-memory indexes, `taps` as (name, formulae)
+    pub fn repo(core: &[FormulaStable], taps: Vec<(&str, Vec<FormulaStable>)>) -> Repo {
+        let index = |formulae: &[FormulaStable]| {
+            Index::from_payload(IndexHeader::default(), build_payload(formulae).unwrap()).unwrap()
+        };
+
+        Repo {
+            index: index(core),
+            taps: taps
+                .into_iter()
+                .map(|(name, formulae)| {
+                    let mut index = index(&formulae);
+                    index.namespace = Some(name.to_string());
+
+                    Tap {
+                        name: name.to_string(),
+                        preferred: false,
+                        index,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn formula(name: &str) -> FormulaStable {
+        FormulaStable {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{formula, repo};
+    use super::*;
+    use crate::jws::testing::{keys, sign};
+    use crate::test_server::{serve, Response};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const LAST_MODIFIED: &str = "Wed, 01 May 2024 00:00:00 GMT";
+
+    fn signed_index(version: &str) -> Vec<u8> {
+        let payload = format!(
+            r#"[{{"name": "liba", "versions": {{"stable": "{}"}}}}]"#,
+            version
+        );
+
+        sign("test-1", "PS512", false, &payload, &payload)
+    }
+
+    fn version(index: &Index) -> String {
+        index.get("liba").unwrap().load().unwrap().version
+    }
+
+    #[tokio::test]
+    async fn conditional_refresh() {
+        let changed = Arc::new(AtomicBool::new(false));
+        let conditions = Arc::new(Mutex::new(vec![]));
+
+        let url = {
+            let (changed, conditions) = (changed.clone(), conditions.clone());
+
+            serve(move |req| {
+                conditions.lock().unwrap().push((
+                    req.header("if-none-match").map(str::to_string),
+                    req.header("if-modified-since").map(str::to_string),
+                ));
+
+                if changed.load(Ordering::SeqCst) {
+                    Response::new(200, signed_index("2.0")).header("ETag", "\"v2\"")
+                } else if req.header("if-none-match") == Some("\"v1\"") {
+                    Response::new(304, "")
+                } else {
+                    Response::new(200, signed_index("1.0"))
+                        .header("ETag", "\"v1\"")
+                        .header("Last-Modified", LAST_MODIFIED)
+                }
+            })
+            .await
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let index_path = Utf8Path::from_path(dir.path()).unwrap().join("index.bin");
+        let source = Source::Core {
+            urls: vec![format!("{}/formula.jws.json", url)],
+            keys: keys("test-1"),
+        };
+        let reopen = || Index::open(&index_path, &source.source_url()).unwrap();
+
+        let (index, updated) = refresh_index(&source, &index_path, None, false)
+            .await
+            .unwrap();
+        assert!(updated);
+        assert_eq!(version(&index), "1.0");
+        assert_eq!(index.header.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(index.header.last_modified.as_deref(), Some(LAST_MODIFIED));
+
+        // Unchanged: the cache is kept and only its age is reset
+        let mut cached = reopen();
+        cached.header.fetched_at = 1;
+
+        let (index, updated) = refresh_index(&source, &index_path, Some(cached), false)
+            .await
+            .unwrap();
+        assert!(!updated);
+        assert_eq!(version(&index), "1.0");
+        assert!(reopen().header.fetched_at > 1);
+        assert_eq!(
+            conditions.lock().unwrap().last().unwrap(),
+            &(Some("\"v1\"".to_string()), Some(LAST_MODIFIED.to_string()))
+        );
+
+        // Changed: the cache is replaced
+        changed.store(true, Ordering::SeqCst);
+
+        let (index, updated) = refresh_index(&source, &index_path, Some(reopen()), false)
+            .await
+            .unwrap();
+        assert!(updated);
+        assert_eq!(version(&index), "2.0");
+        assert_eq!(version(&reopen()), "2.0");
+        assert_eq!(reopen().header.etag.as_deref(), Some("\"v2\""));
+    }
+
+    #[test]
+    fn tap_names_are_qualified() {
+        let tool = FormulaStable {
+            name: "tool".to_string(),
+            deps: vec!["liba".to_string(), "zlib".to_string()],
+            uses_from_macos: vec![MacosDep {
+                name: "libb".to_string(),
+                since: None,
+            }],
+            conflicts: vec![Conflict {
+                name: "tool-ng".to_string(),
+                reason: None,
+            }],
+            ..Default::default()
+        };
+        let formulae = ["liba", "libb", "tool-ng"].map(formula);
+
+        let qualified = qualify_tap_deps("mycorp", [vec![tool], formulae.to_vec()].concat());
+
+        assert_eq!(qualified[0].deps, ["mycorp/liba", "zlib"]);
+        assert_eq!(qualified[0].uses_from_macos[0].name, "mycorp/libb");
+        assert_eq!(qualified[0].conflicts[0].name, "mycorp/tool-ng");
+
+        let repo = repo(&[formula("liba")], vec![("mycorp", qualified)]);
+        let liba = repo.get("mycorp/liba").unwrap();
+        assert_eq!(liba.full_name(), "mycorp/liba");
+        assert_eq!(
+            repo.get("mycorp/tool").unwrap().deps().collect::<Vec<_>>(),
+            ["mycorp/liba", "zlib"]
+        );
+    }
+
+    #[test]
+    fn suggestions_for_a_typo() {
+        let wget = FormulaStable {
+            aliases: vec!["wget2".to_string()],
+            ..formula("wget")
+        };
+        let postgresql = FormulaStable {
+            aliases: vec!["postgres".to_string()],
+            ..formula("postgresql@16")
+        };
+        let repo = repo(
+            &[wget, formula("jq"), postgresql],
+            vec![("mycorp", vec![formula("wgetx")])],
+        );
+
+        assert_eq!(repo.suggest("wgt"), ["wget"]);
+        assert_eq!(
+            repo.resolve("wgt").err().unwrap().to_string(),
+            "No formula named wgt, did you mean wget?"
+        );
+
+        assert_eq!(repo.suggest("wgetz"), ["mycorp/wgetx", "wget"]);
+
+        // Only the alias is close
+        assert_eq!(repo.suggest("postgre"), ["postgresql@16"]);
 
-        Ok(repo)
+        assert!(repo.suggest("kubectl").is_empty());
     }
 }
@@ -0,0 +1,94 @@
+use anyhow::Error;
+use rand::Rng;
+use reqwest::StatusCode;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{RETRY_BASE_DELAY, RETRY_MAX_DELAY};
+use crate::fetch_install::BottleFetchErr;
+use crate::http::Stalled;
+
+/// Whether another attempt can succeed where this one failed
+#[derive(Debug, PartialEq)]
+pub enum ErrorClass {
+    /// Carries the delay the server asked for, if any
+    Transient(Option<Duration>),
+    Permanent,
+}
+
+fn classify_status(status: StatusCode) -> ErrorClass {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::REQUEST_TIMEOUT => ErrorClass::Transient(None),
+        s if s.is_server_error() => ErrorClass::Transient(None),
+        _ => ErrorClass::Permanent,
+    }
+}
+
+pub fn classify(e: &Error) -> ErrorClass {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<BottleFetchErr>() {
+            return match e {
+                BottleFetchErr::RetryAfter(_, delay) => ErrorClass::Transient(Some(*delay)),
+                BottleFetchErr::BadStatus(status) => classify_status(*status),
+                // The partial file is dropped in both cases, the next attempt starts over
+                BottleFetchErr::ResumedDownloadCorrupted
+                | BottleFetchErr::BadContentRange(..)
+                | BottleFetchErr::ChangedOnServer => ErrorClass::Transient(None),
+                BottleFetchErr::DownloadCorrupted => ErrorClass::Permanent,
+            };
+        }
+
+        // Resumed on the next attempt
+        if cause.is::<Stalled>() {
+            return ErrorClass::Transient(None);
+        }
+
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(status) => classify_status(status),
+                None if e.is_builder() || e.is_redirect() => ErrorClass::Permanent,
+                None => ErrorClass::Transient(None),
+            };
+        }
+
+        // Network errors from the body stream, as opposed to e.g. a full disk
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::Interrupted => ErrorClass::Transient(None),
+                _ => ErrorClass::Permanent,
+            };
+        }
+    }
+
+    ErrorClass::Permanent
+}
+
+/// Parses `Retry-After`, which is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    value.parse().map(Duration::from_secs).ok().or_else(|| {
+        let date = httpdate::parse_http_date(value).ok()?;
+        Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+    })
+}
+
+/// Delay before the attempt after `attempt`, exponential with jitter
+///
+/// Returns `None` if the server wants us to wait for longer than we are willing to
+pub fn backoff(attempt: u64, retry_after: Option<Duration>) -> Option<Duration> {
+    if let Some(delay) = retry_after {
+        return (delay <= RETRY_MAX_DELAY).then_some(delay);
+    }
+
+    let ceil = RETRY_BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RETRY_MAX_DELAY);
+
+    Some(rand::thread_rng().gen_range(ceil / 2..=ceil))
+}
@@ -0,0 +1,318 @@
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use indicatif::ProgressBar;
+use reqwest::header::{IF_RANGE, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::task::JoinSet;
+
+use crate::auth::authed_get;
+use crate::config::FETCH_SEGMENTS;
+use crate::fetch_install::{parse_content_range, status_error, BottleFetchErr};
+use crate::http::{client, idle_timeout};
+use crate::throttle;
+use crate::util::{file_digest, fmt_digest};
+
+/// Progress of a segmented download, stored next to the partial file
+#[derive(Serialize, Deserialize)]
+pub struct SegmentState {
+    url: String,
+    validator: String,
+    size: u64,
+    /// (start, end, bytes done) with `end` exclusive
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl SegmentState {
+    pub fn new(url: &str, size: u64, validator: String) -> SegmentState {
+        let segment_len = size.div_ceil(FETCH_SEGMENTS as u64).max(1);
+
+        let segments = (0..size)
+            .step_by(segment_len as usize)
+            .map(|start| (start, (start + segment_len).min(size), 0))
+            .collect();
+
+        SegmentState {
+            url: url.to_string(),
+            validator,
+            size,
+            segments,
+        }
+    }
+
+    /// The state holds for the URL the segments were started from, mirrors
+    /// have validators of their own
+    pub fn is_for(&self, url: &str) -> bool {
+        self.url == url
+    }
+}
+
+fn state_path(path: &Utf8Path) -> Utf8PathBuf {
+    path.with_file_name(format!("{}.segments", path.file_name().unwrap()))
+}
+
+/// The state of an interrupted segmented download, if there is one
+pub async fn read_state(path: &Utf8Path) -> Option<SegmentState> {
+    let data = tokio::fs::read(state_path(path)).await.ok()?;
+
+    bincode::deserialize(&data).ok()
+}
+
+pub async fn remove_state(path: &Utf8Path) {
+    let _ = tokio::fs::remove_file(state_path(path)).await;
+}
+
+async fn write_state(path: &Utf8Path, state: &SegmentState, done: &[Arc<AtomicU64>]) {
+    let state = SegmentState {
+        url: state.url.clone(),
+        validator: state.validator.clone(),
+        size: state.size,
+        segments: state
+            .segments
+            .iter()
+            .zip(done)
+            .map(|(&(start, end, _), done)| (start, end, done.load(Ordering::Acquire)))
+            .collect(),
+    };
+
+    if let Ok(data) = bincode::serialize(&state) {
+        let _ = tokio::fs::write(state_path(path), data).await;
+    }
+}
+
+async fn fetch_segment(
+    url: String,
+    path: Utf8PathBuf,
+    validator: String,
+    (start, end): (u64, u64),
+    done: Arc<AtomicU64>,
+    progress: ProgressBar,
+) -> Result<()> {
+    let from = start + done.load(Ordering::Acquire);
+
+    if from >= end {
+        return Ok(());
+    }
+
+    let _slot = throttle::host_slot(&url).await;
+
+    let mut resp = idle_timeout(authed_get(client()?, &url, |req| {
+        req.header(RANGE, format!("bytes={}-{}", from, end - 1))
+            .header(IF_RANGE, &validator)
+    }))
+    .await?;
+
+    // A full response means that the file has changed since the download started
+    match resp.status() {
+        StatusCode::PARTIAL_CONTENT => {}
+        StatusCode::OK => return Err(BottleFetchErr::ChangedOnServer.into()),
+        _ => return Err(status_error(&resp).into()),
+    }
+
+    let (resp_start, _) = parse_content_range(resp.headers())
+        .context("malformed Content-Range in a partial response")?;
+
+    if resp_start != from {
+        return Err(BottleFetchErr::BadContentRange(resp_start, from).into());
+    }
+
+    let mut file = OpenOptions::new().write(true).open(&path).await?;
+    file.seek(SeekFrom::Start(from)).await?;
+
+    while let Some(chunk) = idle_timeout(resp.chunk()).await? {
+        throttle::consume(chunk.len()).await;
+
+        let remaining = end - start - done.load(Ordering::Acquire);
+        let chunk = &chunk[..chunk.len().min(remaining as usize)];
+
+        file.write_all(chunk).await?;
+        // Only count what has reached the file, the state is persisted from these counters
+        file.flush().await?;
+
+        done.fetch_add(chunk.len() as _, Ordering::Release);
+        progress.inc(chunk.len() as _);
+    }
+
+    if start + done.load(Ordering::Acquire) < end {
+        bail!("segment {}-{} ended prematurely", start, end);
+    }
+
+    Ok(())
+}
+
+/// Fetches byte ranges of the file concurrently into a preallocated file,
+/// then verifies the checksum of the whole
+pub async fn segmented_get(
+    url: &str,
+    path: &Utf8Path,
+    state: SegmentState,
+    checksum: &str,
+    progress: &mut ProgressBar,
+) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    file.set_len(state.size).await?;
+    drop(file);
+
+    let done = state
+        .segments
+        .iter()
+        .map(|&(_, _, done)| Arc::new(AtomicU64::new(done)))
+        .collect::<Vec<_>>();
+
+    write_state(path, &state, &done).await;
+
+    let resumed = state.segments.iter().any(|&(_, _, done)| done > 0);
+
+    progress.set_message(format!("{} segments", state.segments.len()));
+    progress.set_length(state.size);
+    progress.set_position(done.iter().map(|d| d.load(Ordering::Acquire)).sum());
+
+    let mut js = JoinSet::new();
+
+    for (&(start, end, _), done) in state.segments.iter().zip(&done) {
+        js.spawn(fetch_segment(
+            url.to_string(),
+            path.to_owned(),
+            state.validator.clone(),
+            (start, end),
+            done.clone(),
+            progress.clone(),
+        ));
+    }
+
+    let mut result = Ok(());
+    let mut save_ticker = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            res = js.join_next() => match res {
+                Some(res) => {
+                    // Segments aborted after the first failure only report cancellation
+                    if let (Err(e), true) = (res.map_err(Into::into).and_then(|r| r), result.is_ok()) {
+                        js.abort_all();
+                        result = Err(e);
+                    }
+                }
+                None => break,
+            },
+            _ = save_ticker.tick() => write_state(path, &state, &done).await,
+        }
+    }
+
+    write_state(path, &state, &done).await;
+    result?;
+
+    progress.set_message("verifying integrity...");
+    progress.set_position(0);
+
+    let mut file = OpenOptions::new().read(true).open(path).await?;
+    let digest = fmt_digest(file_digest(&mut file, progress).await?);
+
+    remove_state(path).await;
+
+    if digest == checksum {
+        Ok(())
+    } else if resumed {
+        Err(BottleFetchErr::ResumedDownloadCorrupted.into())
+    } else {
+        Err(BottleFetchErr::DownloadCorrupted.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{ranged, serve};
+    use std::sync::Mutex;
+
+    const VALIDATOR: &str = "\"v1\"";
+
+    fn bottle() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn resumes_unfinished_segments() {
+        let body = bottle();
+        let served = body.clone();
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let log = ranges.clone();
+        let url = serve(move |req| {
+            log.lock()
+                .unwrap()
+                .push(req.header("range").unwrap().to_string());
+            ranged(&req, &served, VALIDATOR)
+        })
+        .await;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("bottle.incomplete");
+
+        // The first segment is done and the second one is halfway through
+        let mut state = SegmentState::new(&url, body.len() as u64, VALIDATOR.to_string());
+        let (first_end, second_end) = (state.segments[0].1, state.segments[1].1);
+        let halfway = first_end + (second_end - first_end) / 2;
+        state.segments[0].2 = first_end;
+        state.segments[1].2 = halfway - first_end;
+
+        let mut partial = body[..halfway as usize].to_vec();
+        partial.resize(body.len(), 0);
+        std::fs::write(&path, partial).unwrap();
+
+        let done = state
+            .segments
+            .iter()
+            .map(|&(_, _, done)| Arc::new(AtomicU64::new(done)))
+            .collect::<Vec<_>>();
+        write_state(&path, &state, &done).await;
+
+        let state = read_state(&path).await.unwrap();
+        assert!(state.is_for(&url));
+
+        let checksum = sha256::digest(&body[..]);
+        segmented_get(&url, &path, state, &checksum, &mut ProgressBar::hidden())
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(read_state(&path).await.is_none());
+
+        let ranges = ranges.lock().unwrap();
+        assert_eq!(ranges.len(), FETCH_SEGMENTS - 1);
+        assert!(ranges.contains(&format!("bytes={}-{}", halfway, second_end - 1)));
+        assert!(!ranges.iter().any(|r| r.starts_with("bytes=0-")));
+    }
+
+    #[tokio::test]
+    async fn changed_file_fails_segments() {
+        let body = bottle();
+        let url = serve(move |req| ranged(&req, &body, "\"v2\"")).await;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("bottle.incomplete");
+
+        let state = SegmentState::new(&url, 100_000, VALIDATOR.to_string());
+        let err = segmented_get(&url, &path, state, "", &mut ProgressBar::hidden())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BottleFetchErr::ChangedOnServer)
+        ));
+    }
+}
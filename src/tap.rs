@@ -0,0 +1,103 @@
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SAMOGON_DATA_DIR;
+use crate::repo::fetch_tap_index;
+
+/// An extra formula index in the same JSON schema as formulae.brew.sh
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TapSource {
+    pub name: String,
+    pub url: String,
+    /// Bare names resolve to this tap before core
+    #[serde(default)]
+    pub preferred: bool,
+}
+
+fn taps_path() -> Utf8PathBuf {
+    SAMOGON_DATA_DIR.join("taps.json")
+}
+
+pub fn index_path(name: &str) -> Utf8PathBuf {
+    SAMOGON_DATA_DIR.join("taps").join(format!("{}.bin", name))
+}
+
+/// Taps in the order they were added
+pub fn read_taps() -> Result<Vec<TapSource>> {
+    let path = taps_path();
+
+    match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).context(anyhow!("{} is malformed", path)),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+fn write_taps(taps: &[TapSource]) -> Result<()> {
+    std::fs::create_dir_all(&*SAMOGON_DATA_DIR)?;
+    std::fs::write(taps_path(), serde_json::to_vec_pretty(taps)?)?;
+
+    Ok(())
+}
+
+/// Local paths are turned into `file://` URLs
+fn normalize_url(url: &str) -> Result<String> {
+    if url.contains("://") {
+        return Ok(url.to_string());
+    }
+
+    let path = Utf8Path::new(url)
+        .canonicalize_utf8()
+        .context(anyhow!("{} is neither a URL nor an existing file", url))?;
+
+    Ok(format!("file://{}", path))
+}
+
+/// Registers a tap and fetches its index, returns the number of its formulae
+pub async fn add_tap(name: &str, url: &str, preferred: bool) -> Result<usize> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("tap names may only contain letters, digits, - and _");
+    }
+    if name == "homebrew" || name == "core" {
+        bail!("{} is reserved for the core index", name);
+    }
+
+    let mut taps = read_taps()?;
+
+    if taps.iter().any(|t| t.name == name) {
+        bail!("tap {} already exists, remove it first", name);
+    }
+
+    let source = TapSource {
+        name: name.to_string(),
+        url: normalize_url(url)?,
+        preferred,
+    };
+
+    let count = fetch_tap_index(&source).await?;
+
+    taps.push(source);
+    write_taps(&taps)?;
+
+    Ok(count)
+}
+
+pub fn remove_tap(name: &str) -> Result<()> {
+    let mut taps = read_taps()?;
+    let len = taps.len();
+
+    taps.retain(|t| t.name != name);
+
+    if taps.len() == len {
+        bail!("no tap named {}", name);
+    }
+
+    write_taps(&taps)?;
+    let _ = std::fs::remove_file(index_path(name));
+
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! A minimal HTTP/1.1 server for tests, one request per connection
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[derive(Clone)]
+pub struct Request {
+    /// Names are lowercased
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Bytes per second the body is sent at, unlimited if `None`
+    pub rate: Option<usize>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: body.into(),
+            rate: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn rate(mut self, rate: usize) -> Response {
+        self.rate = Some(rate);
+        self
+    }
+}
+
+/// Responds with `body` and its `validator` as the `ETag`, honouring `Range`
+/// unless `If-Range` is stale
+pub fn ranged(req: &Request, body: &[u8], validator: &str) -> Response {
+    let range = req
+        .header("range")
+        .and_then(|r| r.strip_prefix("bytes="))
+        .and_then(|r| r.split_once('-'))
+        .filter(|_| req.header("if-range").is_none_or(|v| v == validator));
+
+    let Some((start, end)) = range else {
+        return Response::new(200, body)
+            .header("ETag", validator)
+            .header("Accept-Ranges", "bytes");
+    };
+
+    let start: usize = start.parse().unwrap();
+    let end = end.parse().unwrap_or(body.len() - 1).min(body.len() - 1);
+
+    if start >= body.len() {
+        return Response::new(416, "").header("Content-Range", format!("bytes */{}", body.len()));
+    }
+
+    Response::new(206, &body[start..=end])
+        .header("ETag", validator)
+        .header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end, body.len()),
+        )
+}
+
+/// Serves `handler` on a random local port, returns the base URL
+pub async fn serve(handler: impl Fn(Request) -> Response + Send + Sync + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let handler = handler.clone();
+
+            tokio::spawn(async move {
+                let mut head = vec![];
+                let mut buf = [0u8; 1024];
+
+                while !head.ends_with(b"\r\n\r\n") {
+                    match conn.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend(&buf[..n]),
+                    }
+                }
+
+                let head = String::from_utf8_lossy(&head);
+                let headers = head
+                    .lines()
+                    .skip(1)
+                    .filter_map(|l| l.split_once(':'))
+                    .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+                    .collect();
+
+                let resp = handler(Request { headers });
+
+                let mut out = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    resp.status,
+                    resp.body.len()
+                );
+                for (k, v) in &resp.headers {
+                    out += &format!("{}: {}\r\n", k, v);
+                }
+                out += "\r\n";
+
+                let _ = conn.write_all(out.as_bytes()).await;
+                let Some(rate) = resp.rate else {
+                    let _ = conn.write_all(&resp.body).await;
+                    return;
+                };
+
+                // Ten chunks a second
+                for chunk in resp.body.chunks((rate / 10).max(1)) {
+                    if conn.write_all(chunk).await.is_err() {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            });
+        }
+    });
+
+    format!("http://{}", addr)
+}
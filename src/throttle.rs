@@ -0,0 +1,84 @@
+use lazy_static::lazy_static;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::DEFAULT_HOST_CONNECTIONS;
+
+/// A token bucket shared by all transfers, refilled at `rate` bytes per second
+struct Bandwidth {
+    rate: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+static BANDWIDTH: OnceLock<Bandwidth> = OnceLock::new();
+static HOST_CONNECTIONS: OnceLock<usize> = OnceLock::new();
+
+lazy_static! {
+    static ref HOST_SLOTS: Mutex<HashMap<String, Arc<Semaphore>>> = Mutex::new(HashMap::new());
+}
+
+/// Must be called before any transfer starts
+pub fn init(max_bandwidth: Option<u64>, host_connections: usize) {
+    if let Some(rate) = max_bandwidth {
+        let rate = rate as f64;
+        let _ = BANDWIDTH.set(Bandwidth {
+            rate,
+            bucket: Mutex::new((rate, Instant::now())),
+        });
+    }
+
+    let _ = HOST_CONNECTIONS.set(host_connections.max(1));
+}
+
+/// Waits until `bytes` more bytes may be received
+///
+/// The bucket goes into debt instead of making big chunks wait for a full
+/// bucket, the debt is paid off by sleeping
+pub async fn consume(bytes: usize) {
+    let Some(bw) = BANDWIDTH.get() else {
+        return;
+    };
+
+    let wait = {
+        let mut bucket = bw.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+
+        let now = Instant::now();
+        // At most a second worth of burst
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * bw.rate).min(bw.rate);
+        *last = now;
+        *tokens -= bytes as f64;
+
+        (*tokens < 0.).then(|| Duration::from_secs_f64(-*tokens / bw.rate))
+    };
+
+    if let Some(wait) = wait {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Waits for a free connection to the host of `url`, the connection is
+/// counted until the permit is dropped
+pub async fn host_slot(url: &str) -> OwnedSemaphorePermit {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default();
+
+    let slots = HOST_SLOTS
+        .lock()
+        .unwrap()
+        .entry(host)
+        .or_insert_with(|| {
+            Arc::new(Semaphore::new(
+                *HOST_CONNECTIONS.get().unwrap_or(&DEFAULT_HOST_CONNECTIONS),
+            ))
+        })
+        .clone();
+
+    // The semaphore is never closed
+    slots.acquire_owned().await.unwrap()
+}
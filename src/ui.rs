@@ -1,4 +1,5 @@
 use std::cmp::min;
+use std::collections::HashMap;
 
 use anyhow::{bail, Context, Result};
 use colored::Colorize;
@@ -8,11 +9,17 @@ use itertools::Itertools;
 use textwrap::{core::Word, WrapAlgorithm};
 use tokio::task::spawn_blocking;
 
+use crate::config::HOMEBREW_PREFIX;
 use crate::repo::FormulaStable;
 
 pub const PROGRESS_CHARS: &'static str = "━╾╴─";
 
-pub async fn confirm_install(pkgs: &[FormulaStable]) -> Result<bool> {
+/// `requested_as` maps the real names of formulae requested by an alias or
+/// an old name to what the user typed
+pub async fn confirm_install(
+    pkgs: &[FormulaStable],
+    requested_as: &HashMap<String, String>,
+) -> Result<bool> {
     if pkgs.len() == 0 {
         bail!("nothing to confirm");
     }
@@ -42,7 +49,15 @@ pub async fn confirm_install(pkgs: &[FormulaStable]) -> Result<bool> {
 
     let pieces = pkgs
         .iter()
-        .map(|p| format!("{} of {}, ", p.name.bold(), p.version_fmt().green()))
+        .map(|p| match requested_as.get(&p.name) {
+            Some(alias) => format!(
+                "{} (for {}) of {}, ",
+                p.name.bold(),
+                alias,
+                p.version_fmt().green()
+            ),
+            None => format!("{} of {}, ", p.name.bold(), p.version_fmt().green()),
+        })
         .collect::<Vec<_>>();
     let words = pieces.iter().map(|s| Word::from(s)).collect::<Vec<_>>();
 
@@ -60,7 +75,28 @@ pub async fn confirm_install(pkgs: &[FormulaStable]) -> Result<bool> {
         .map(|l| format!("{}{}", " ".repeat(tabs as _), l))
         .join("\n");
 
-    println!("{}\n\n", text);
+    println!("{}\n", text);
+
+    for p in pkgs {
+        let notes = [
+            ("was", &p.disabled, "disabled"),
+            ("is", &p.deprecated, "deprecated"),
+        ];
+
+        for (verb, deprecation, what) in notes {
+            if let Some(deprecation) = deprecation {
+                println!(
+                    " {} {} {} {}",
+                    "!".bold(),
+                    p.name.bold(),
+                    verb,
+                    deprecation.describe(what).yellow()
+                );
+            }
+        }
+    }
+
+    println!();
 
     // TODO maybe estimate total size
 
@@ -75,6 +111,121 @@ pub async fn confirm_install(pkgs: &[FormulaStable]) -> Result<bool> {
     Ok(result)
 }
 
+fn bottle_status(formula: &FormulaStable, platform: &str) -> String {
+    if !formula.is_bottled() {
+        "no bottle".red().to_string()
+    } else if formula.bottle_for(platform).is_none() {
+        format!("no bottle for {}", platform).yellow().to_string()
+    } else {
+        "bottled".green().to_string()
+    }
+}
+
+pub fn print_search_results(results: &[FormulaStable], platform: &str) {
+    if results.is_empty() {
+        println!(" -> nothing found");
+        return;
+    }
+
+    for f in results {
+        let status = if f.bottle_for(platform).is_none() {
+            format!(" ({})", bottle_status(f, platform))
+        } else {
+            String::new()
+        };
+
+        println!(
+            " -> {} {}{} -- {}",
+            f.name.bold(),
+            f.version_fmt().green(),
+            status,
+            f.description
+        );
+    }
+}
+
+pub fn print_info(formula: &FormulaStable, platform: &str) {
+    println!(
+        " -> {} {}: {}",
+        formula.name.bold(),
+        formula.version_fmt().green(),
+        formula.description
+    );
+
+    let deps = formula.platform_deps(platform).join(", ");
+
+    println!("    deps: {}", if deps.is_empty() { "none" } else { &deps });
+
+    let aliases = formula.aliases.iter().chain(&formula.oldnames).join(", ");
+    if !aliases.is_empty() {
+        println!("    also known as: {}", aliases);
+    }
+
+    println!("    bottle: {}", bottle_status(formula, platform));
+
+    if let Some(reason) = &formula.keg_only {
+        println!(
+            "    keg-only: {}",
+            if reason.is_empty() { "yes" } else { reason }
+        );
+    }
+
+    if let Some(disabled) = &formula.disabled {
+        println!("    {}", disabled.describe("disabled").red());
+    } else if let Some(deprecated) = &formula.deprecated {
+        println!("    {}", deprecated.describe("deprecated").yellow());
+    }
+
+    if let Some(caveats) = caveats(formula) {
+        println!("    caveats:");
+        for line in caveats.lines() {
+            println!("      {}", line);
+        }
+    }
+}
+
+/// The formula's own caveats followed by the keg-only one
+pub fn caveats(formula: &FormulaStable) -> Option<String> {
+    let parts = formula
+        .caveats
+        .as_deref()
+        .map(|c| c.trim_end().to_string())
+        .into_iter()
+        .chain(keg_only_caveat(formula))
+        .collect::<Vec<_>>();
+
+    (!parts.is_empty()).then(|| parts.join("\n\n"))
+}
+
+/// How to use a keg-only formula that is only reachable through `opt`
+fn keg_only_caveat(formula: &FormulaStable) -> Option<String> {
+    let reason = formula.keg_only.as_ref()?;
+    let name = formula.keg_name();
+    let opt = HOMEBREW_PREFIX.join("opt").join(name);
+
+    let mut text = format!(
+        "{} is keg-only, which means it was not symlinked into {}",
+        name, *HOMEBREW_PREFIX
+    );
+    if !reason.is_empty() {
+        text += &format!(",\nbecause {}", reason);
+    }
+    text += ".\n";
+
+    text += &format!(
+        "\nIf you need to have {} first in your PATH, run:\n  \
+        echo 'export PATH=\"{}/bin:$PATH\"' >> ~/.profile\n",
+        name, opt
+    );
+    text += &format!(
+        "\nFor pkg-config to find {} you may need to set:\n  \
+        export PKG_CONFIG_PATH=\"{}/lib/pkgconfig\"",
+        name, opt
+    );
+
+    Some(text)
+}
+
 pub fn total_bar_style() -> ProgressStyle {
     ProgressStyle::with_template(
         // "  total {wide_msg:<} after {elapsed} got {bytes:<7.green} eta {eta:.blue}    {bar:60.green/black}"
@@ -32,6 +32,27 @@ pub fn normalize_path(path: &Utf8Path) -> Utf8PathBuf {
     ret
 }
 
+/// Levenshtein distance between two strings, counted in chars
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let next = (diag + (ca != *cb) as usize)
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+
+    row[b.len()]
+}
+
 pub fn fmt_digest(ctx: Sha256) -> String {
     hex::encode(ctx.finalize())
 }
@@ -51,3 +72,19 @@ pub async fn file_digest(file: &mut File, progress: &mut ProgressBar) -> Result<
 
     Ok(hash_ctx)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("wget", "wget"), 0);
+        assert_eq!(edit_distance("wgte", "wget"), 2);
+        assert_eq!(edit_distance("wge", "wget"), 1);
+        assert_eq!(edit_distance("", "jq"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        // Chars rather than bytes
+        assert_eq!(edit_distance("héllo", "hello"), 1);
+    }
+}